@@ -7,6 +7,7 @@ use ::gtk;
 use ::log::LevelFilter;
 use ::config_rs::ConfigError;
 use ::gui;
+use ::vfio_motion_common::input::Hotplug;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Logging {
@@ -31,6 +32,7 @@ impl Logging {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Libvirt {
     pub uri: String,
+    pub hotplug: Hotplug,
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Http {
@@ -44,6 +46,9 @@ pub struct Config {
     pub native: bool,
 
     pub domain: String,
+    /// by-id names or paths, see `evdev::Specifier`. Globs and `vendor:product` only work with the http backend,
+    /// which resolves them on the host. `usb:vendor:product` or `usb:bus.device` passes a whole USB device
+    /// through instead.
     pub devices: Vec<String>,
 
     pub service_startup: bool,
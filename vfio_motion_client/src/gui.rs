@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::rc::Rc;
+use std::sync::{mpsc, Arc};
 use std::cell::{Cell, RefCell};
 use std::ptr;
 use std::fs;
@@ -19,9 +20,12 @@ use ::gtk;
 use gtk::prelude::*;
 use gtk::{MessageDialog, DialogFlags, MessageType, ButtonsType};
 use ::reqwest;
+use ::futures::{future, Future};
+use ::futures_cpupool::CpuPool;
 
-use ::vfio_motion_common::libvirt::Connection;
-use ::vfio_motion_common::input::{self, Input, NativeInput, HttpInput};
+use ::vfio_motion_common::input::{self, Status};
+use ::vfio_motion_common::evdev::EvdevInfo;
+use ::vfio_motion_common::async_input::{self, AsyncInput, InputFuture};
 
 use ::config::Config;
 
@@ -99,11 +103,104 @@ impl SharedLogger for MessageBoxLogger {
     }
 }
 
+/// Run `future` on `pool` and call `done` from the GTK main loop once it resolves
+fn when_ready<T, F, D>(pool: &CpuPool, future: F, done: D)
+where
+    T: Send + 'static,
+    F: Future<Item = T, Error = input::Error> + Send + 'static,
+    D: FnOnce(Result<T, input::Error>) + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(future.then(move |res| {
+        let _ = tx.send(res);
+        Ok::<(), ()>(())
+    })).forget();
+
+    let mut done = Some(done);
+    gtk::timeout_add(50, move || match rx.try_recv() {
+        Ok(res) => {
+            if let Some(d) = done.take() {
+                d(res);
+            }
+            Continue(false)
+        },
+        Err(mpsc::TryRecvError::Empty) => Continue(true),
+        Err(mpsc::TryRecvError::Disconnected) => Continue(false),
+    });
+}
+
+fn refresh_devices(pool: &CpuPool, input: &Arc<AsyncInput>, conf: &Config, devices: &gtk::ListStore) {
+    for (i, dev) in conf.devices.iter().enumerate() {
+        let status = input.device(&conf.domain, dev).and_then(|d| d.status());
+        let w_devices = devices.downgrade();
+        let dev = dev.clone();
+        when_ready(pool, status, move |res| {
+            let devices = upgrade_weak!(w_devices);
+            let status = res.unwrap_or_else(Status::Unknown);
+            if let Status::Unknown(ref e) = status {
+                info!("state of device at '{}' is unknown: {}", dev, e);
+            }
+
+            if let Some(tree_iter) = devices.iter_nth_child(None, i as i32) {
+                devices.set_value(&tree_iter, 1, &status.is_attached().to_value());
+                devices.set_value(&tree_iter, 2, &status.is_unknown().to_value());
+            }
+        });
+    }
+}
+
+/// Ask the user to pick one of the host's evdevs, returns its preferred path
+fn pick_evdev(window: &gtk::Window, evdevs: &[EvdevInfo]) -> Option<String> {
+    let dialog = gtk::Dialog::new_with_buttons(Some("Add device"), Some(window), DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+                                               &[("Cancel", gtk::ResponseType::Cancel.into()), ("Add", gtk::ResponseType::Accept.into())]);
+    let picker = gtk::ComboBoxText::new();
+    for evdev in evdevs {
+        let caps = evdev.capabilities.iter().map(|c| format!("{:?}", c)).collect::<Vec<_>>().join(", ");
+        picker.append(Some(evdev.preferred_path()), &format!("{} [{}] ({})", evdev.name, caps, evdev.preferred_path()));
+    }
+    picker.set_active(0);
+    dialog.get_content_area().add(&picker);
+    picker.show();
+
+    let picked = match gtk::ResponseType::from(dialog.run()) {
+        gtk::ResponseType::Accept => picker.get_active_id(),
+        _ => None
+    };
+    dialog.destroy();
+    picked
+}
+
+fn write_config(conf: &RefCell<Config>, save: &gtk::Button, save_notification: &gtk::InfoBar) {
+    let conf_str = match toml::to_string(&*conf.borrow()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("failed to serialize configuration: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = fs::write(conf.borrow().file(), conf_str) {
+        error!("failed to write configuration to '{}': {}", conf.borrow().file(), e);
+        return;
+    }
+
+    save.set_sensitive(false);
+    save_notification.set_revealed(true);
+    let w_save_notif = save_notification.downgrade();
+    gtk::timeout_add_seconds(2, move || {
+        upgrade_weak!(w_save_notif, Continue(false)).set_revealed(false);
+        Continue(false)
+    });
+    info!("configuration written to {}", conf.borrow().file());
+}
+
+/// Called with whether connecting and reloading succeeded
+type ReloadDone = Box<Fn(bool)>;
 struct ConfigUi {
     config: Rc<RefCell<Config>>,
     conn_changed: Rc<Cell<bool>>,
-    input: Rc<RefCell<Option<Box<Input>>>>,
-    connect_and_reload: Rc<RefCell<Option<Box<Fn() -> bool>>>>,
+    pool: CpuPool,
+    input: Rc<RefCell<Option<Arc<AsyncInput>>>>,
+    connect_and_reload: Rc<RefCell<Option<Box<Fn(ReloadDone)>>>>,
 
     window: gtk::Window,
     save: gtk::Button,
@@ -121,6 +218,9 @@ struct ConfigUi {
 
     // Devices page
     devices: gtk::ListStore,
+    selected_device: gtk::TreeSelection,
+    device_add: gtk::Button,
+    device_remove: gtk::Button,
 }
 impl ConfigUi {
     pub fn new(builder: gtk::Builder, config: &Config) -> ConfigUi {
@@ -139,6 +239,9 @@ impl ConfigUi {
 
         // Devices page
         let devices             = builder.get_object("devices").unwrap();
+        let selected_device     = builder.get_object("selected_device").unwrap();
+        let device_add          = builder.get_object("device_add").unwrap();
+        let device_remove       = builder.get_object("device_remove").unwrap();
 
         let window: gtk::Window = builder.get_object("window").unwrap();
         window.show_all();
@@ -150,6 +253,7 @@ impl ConfigUi {
         ConfigUi {
             config: Rc::new(RefCell::new(config.clone())),
             conn_changed: Rc::new(Cell::new(false)),
+            pool: CpuPool::new(2),
             input: Rc::new(RefCell::new(None)),
             connect_and_reload: Rc::new(RefCell::new(None)),
 
@@ -157,7 +261,7 @@ impl ConfigUi {
             // General page
             libvirt_mode, domains, domain, service_startup, hotkey, libvirt_uri, http_url, log_dir,
             // Devices page
-            devices,
+            devices, selected_device, device_add, device_remove,
         }
     }
 
@@ -167,61 +271,68 @@ impl ConfigUi {
         let w_input = Rc::downgrade(&self.input);
         let w_domains = self.domains.downgrade();
         let w_domain = self.domain.downgrade();
-        self.connect_and_reload.replace(Some(Box::new(clone!(w_conf, w_input, w_domains, w_domain => move || {
-            let conf = upgrade_weak!(w_conf, false);
-            let input = upgrade_weak!(w_input, false);
-            let domains = upgrade_weak!(w_domains, false);
-            let domain = upgrade_weak!(w_domain, false);
+        let w_devices = self.devices.downgrade();
+        let pool = self.pool.clone();
+        self.connect_and_reload.replace(Some(Box::new(move |done: ReloadDone| {
+            let conf = upgrade_weak!(w_conf, done(false));
+            upgrade_weak!(w_domain, done(false)).set_sensitive(false);
 
-            input.replace(if conf.borrow().native {
+            let connect: InputFuture<Arc<AsyncInput>> = if conf.borrow().native {
                 info!("native backend, opening connection to libvirt...");
-                let uri = &conf.borrow().libvirt.uri;
-                let conn = match Connection::open(uri) {
-                    Ok(c) => Some(c),
-                    Err(e) => {
-                        error!("failed to open connection to libvirtd on {}: {}", uri, e);
-                        None
-                    }
-                };
-                match conn {
-                    Some(c) => Some(NativeInput::new(c)),
-                    None => None
-                }
+                async_input::open_native(&conf.borrow().libvirt.uri, conf.borrow().libvirt.hotplug, pool.clone())
             } else {
                 info!("http backend, creating client...");
-                Some(HttpInput::new(reqwest::Client::new(), &conf.borrow().http.url))
-            });
-
-            let input = &*input.borrow();
-            domains.clear();
-            if let Some(i) = input {
-                match i.domains().list() {
-                    Ok(doms) => {
+                Box::new(future::ok(async_input::http(reqwest::Client::new(), &conf.borrow().http.url, pool.clone())))
+            };
+            let reload = connect.and_then(|i| i.domains().list().map(move |doms| (i, doms)));
+
+            let task_pool = pool.clone();
+            when_ready(&pool, reload, clone!(w_conf, w_input, w_domains, w_domain, w_devices => move |res| {
+                let conf = upgrade_weak!(w_conf, done(false));
+                let input = upgrade_weak!(w_input, done(false));
+                let domains = upgrade_weak!(w_domains, done(false));
+                let domain = upgrade_weak!(w_domain, done(false));
+
+                domains.clear();
+                match res {
+                    Ok((i, doms)) => {
                         let mut i_dom = 0;
-                        for (i, dom) in doms.iter().enumerate() {
+                        for (n, dom) in doms.iter().enumerate() {
                             if dom == &conf.borrow().domain {
-                                i_dom = i;
+                                i_dom = n;
                             }
                             domains.set_value(&domains.append(), 0, &gtk::Value::from(dom));
                         }
                         domain.set_sensitive(true);
                         domain.set_active(i_dom as i32);
+
+                        refresh_devices(&task_pool, &i, &conf.borrow(), &upgrade_weak!(w_devices, done(false)));
+                        input.replace(Some(i));
+                        done(true);
                     },
                     Err(e) => {
-                        error!("failed to retrieve domain list: {}", e);
-                        domain.set_sensitive(false);
-                        return false;
+                        error!("failed to connect and retrieve domain list: {}", e);
+                        input.replace(None);
+                        done(false);
                     }
                 }
-            } else {
-                domain.set_sensitive(false);
-                return false;
+            }));
+        })));
+
+        // Devices page
+        {
+            let conf = self.config.borrow();
+            self.devices.clear();
+            for dev in &conf.devices {
+                let tree_iter = self.devices.append();
+                self.devices.set_value(&tree_iter, 0, &dev.to_value());
+                self.devices.set_value(&tree_iter, 1, &false.to_value());
+                self.devices.set_value(&tree_iter, 2, &true.to_value());
             }
-            true
-        }))));
+        }
 
         // General page
-        self.connect_and_reload.borrow().as_ref().unwrap()();
+        self.connect_and_reload.borrow().as_ref().unwrap()(Box::new(|_| {}));
 
         let mut conf = self.config.borrow_mut();
         self.libvirt_mode.set_active_id(if conf.native {
@@ -248,24 +359,6 @@ impl ConfigUi {
             self.hotkey.set_label(&gtk::accelerator_get_label(h_key, h_mod).unwrap());
         }
 
-        // Devices page
-        self.devices.clear();
-        for dev in &conf.devices {
-            let tree_iter = self.devices.append();
-            self.devices.set_value(&tree_iter, 0, &dev.to_value());
-
-            let attached = match *self.input.borrow() {
-                Some(ref i) => match i.device(&conf.domain, dev) {
-                    Ok(ref d) => {
-                        d.attached()
-                    },
-                    Err(_) => false
-                },
-                None => false
-            }.to_value();
-            self.devices.set_value(&tree_iter, 1, &attached);
-        }
-
         self.save_notification.set_default_response(gtk::ResponseType::Close.into());
 
         let w_window = self.window.downgrade();
@@ -284,19 +377,23 @@ impl ConfigUi {
             };
 
             let c_reload = upgrade_weak!(w_c_reload);
-            if !c_reload.borrow().as_ref().unwrap()() {
-                conf.borrow_mut().native = old_state;
-                lvm.set_active_id(match old_state {
-                    true => "native",
-                    false => "http"
-                });
-                return;
-            }
+            let w_lvm = lvm.downgrade();
+            c_reload.borrow().as_ref().unwrap()(Box::new(clone!(w_conf, w_save => move |ok| {
+                let conf = upgrade_weak!(w_conf);
+                if !ok {
+                    conf.borrow_mut().native = old_state;
+                    upgrade_weak!(w_lvm).set_active_id(match old_state {
+                        true => "native",
+                        false => "http"
+                    });
+                    return;
+                }
 
-            if conf.borrow().native != old_state {
-                upgrade_weak!(w_save).set_sensitive(true);
-            }
-            debug!("libvirt mode changed, native?: {}", conf.borrow().native);
+                if conf.borrow().native != old_state {
+                    upgrade_weak!(w_save).set_sensitive(true);
+                }
+                debug!("libvirt mode changed, native?: {}", conf.borrow().native);
+            })));
         }));
         self.domain.connect_changed(clone!(w_conf, w_save => move |d| {
             if let None = d.get_active_id() {
@@ -403,35 +500,79 @@ impl ConfigUi {
             debug!("log dir changed to {}", conf.borrow().logging.dir);
         }));
 
-        let w_save_notif = self.save_notification.downgrade();
-        self.save.connect_clicked(clone!(w_conf, w_save_notif, w_c_reload, w_c_changed => move |s| {
-            if upgrade_weak!(w_c_changed).get() {
-                let c_reload = upgrade_weak!(w_c_reload);
-                if !c_reload.borrow().as_ref().unwrap()() {
+        // Devices page
+        let w_input = Rc::downgrade(&self.input);
+        let w_devices = self.devices.downgrade();
+        let pool = self.pool.clone();
+        self.device_add.connect_clicked(clone!(w_conf, w_save, w_window, w_input, w_devices => move |_| {
+            let input = match *upgrade_weak!(w_input).borrow() {
+                Some(ref i) => i.clone(),
+                None => {
+                    error!("not connected, can't list host devices");
                     return;
                 }
-            }
+            };
 
-            let conf = upgrade_weak!(w_conf);
-            let conf_str = match toml::to_string(&*conf.borrow()) {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("failed to serialize configuration: {}", e);
+            when_ready(&pool, input.evdevs(), clone!(w_conf, w_save, w_window, w_devices => move |res| {
+                let evdevs = match res {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error!("failed to list host devices: {}", e);
+                        return;
+                    }
+                };
+                let evdev = match pick_evdev(&upgrade_weak!(w_window), &evdevs) {
+                    Some(e) => e,
+                    None => return
+                };
+
+                let conf = upgrade_weak!(w_conf);
+                if conf.borrow().devices.contains(&evdev) {
                     return;
                 }
+
+                let devices = upgrade_weak!(w_devices);
+                let tree_iter = devices.append();
+                devices.set_value(&tree_iter, 0, &evdev.to_value());
+                devices.set_value(&tree_iter, 1, &false.to_value());
+                devices.set_value(&tree_iter, 2, &true.to_value());
+
+                debug!("added device {}", evdev);
+                conf.borrow_mut().devices.push(evdev);
+                upgrade_weak!(w_save).set_sensitive(true);
+            }));
+        }));
+        let w_selected = self.selected_device.downgrade();
+        self.device_remove.connect_clicked(clone!(w_conf, w_save, w_devices, w_selected => move |_| {
+            let (_, tree_iter) = match upgrade_weak!(w_selected).get_selected() {
+                Some(s) => s,
+                None => return
             };
-            if let Err(e) = fs::write(conf.borrow().file(), conf_str) {
-                error!("failed to write configuration to '{}': {}", conf.borrow().file(), e);
+
+            let devices = upgrade_weak!(w_devices);
+            let evdev = devices.get_value(&tree_iter, 0).get::<String>().unwrap();
+            devices.remove(&tree_iter);
+
+            let conf = upgrade_weak!(w_conf);
+            conf.borrow_mut().devices.retain(|d| d != &evdev);
+            upgrade_weak!(w_save).set_sensitive(true);
+            debug!("removed device {}", evdev);
+        }));
+
+        let w_save_notif = self.save_notification.downgrade();
+        self.save.connect_clicked(clone!(w_conf, w_save_notif, w_c_reload, w_c_changed => move |s| {
+            if upgrade_weak!(w_c_changed).get() {
+                let c_reload = upgrade_weak!(w_c_reload);
+                let w_s = s.downgrade();
+                c_reload.borrow().as_ref().unwrap()(Box::new(clone!(w_conf, w_save_notif => move |ok| {
+                    if ok {
+                        write_config(&upgrade_weak!(w_conf), &upgrade_weak!(w_s), &upgrade_weak!(w_save_notif));
+                    }
+                })));
                 return;
             }
 
-            s.set_sensitive(false);
-            upgrade_weak!(w_save_notif).set_revealed(true);
-            gtk::timeout_add_seconds(2, clone!(w_save_notif => move || {
-                upgrade_weak!(w_save_notif, Continue(false)).set_revealed(false);
-                Continue(false)
-            }));
-            info!("configuration written to {}", conf.borrow().file());
+            write_config(&upgrade_weak!(w_conf), s, &upgrade_weak!(w_save_notif));
         }));
         self.save_notification.connect_close(|sn| sn.set_revealed(false));
         self.save_notification.connect_response(|sn, res| if gtk::ResponseType::from(res) == gtk::ResponseType::Close {
@@ -11,8 +11,9 @@ extern crate serde_derive;
 extern crate simplelog;
 extern crate config as config_rs;
 extern crate toml;
-extern crate virt;
 extern crate reqwest;
+extern crate futures;
+extern crate futures_cpupool;
 extern crate widestring;
 extern crate libc;
 extern crate winapi;
@@ -23,6 +24,10 @@ extern crate gtk;
 
 extern crate vfio_motion_common;
 
+// `native` would still link libvirt
+#[cfg(all(feature = "native", feature = "remote"))]
+compile_error!("the `native` and `remote` features are exclusive, build with `--no-default-features --features remote`");
+
 #[macro_use]
 mod util;
 pub mod config;
@@ -70,6 +70,7 @@ fn load_config(args: clap::ArgMatches) -> Result<Config, ConfigError> {
     config.set_default("logging.dir", DEFAULT_DIR.to_str().unwrap())?;
     config.set_default("native", true)?;
     config.set_default("libvirt.uri", "qemu+tcp://10.0.122.1/system")?;
+    config.set_default("libvirt.hotplug", "libvirt")?;
     config.set_default("http.url", "http://10.0.122.1:3020")?;
     config.set_default("domain", "gpu")?;
     config.set_default("devices", Vec::new() as Vec<String>)?;
@@ -1,6 +1,5 @@
+use std::sync::Arc;
 use std::error::Error as StdError;
-use std::time::Duration;
-use std::thread;
 
 use ::winapi::um::winuser;
 use ::winapi::um::wincon::{CTRL_C_EVENT, CTRL_CLOSE_EVENT};
@@ -9,8 +8,15 @@ use ::reqwest;
 use ::config::Config;
 use ::win::{self, Hotkey};
 
-use ::vfio_motion_common::libvirt::Connection;
-use ::vfio_motion_common::input::{NativeInput, HttpInput, Device};
+#[cfg(feature = "native")]
+use ::vfio_motion_common::supervisor::{self, Supervisor};
+#[cfg(feature = "remote")]
+use ::vfio_motion_common::remote::{RemoteConnection, RemoteInput};
+use ::vfio_motion_common::input::{Input, HttpInput, Target};
+#[cfg(not(any(feature = "native", feature = "remote")))]
+use ::vfio_motion_common::input::Error as InputError;
+#[cfg(feature = "native")]
+use ::vfio_motion_common::input::NativeInput;
 
 quick_error! {
     #[derive(Debug)]
@@ -23,6 +29,21 @@ quick_error! {
 
 static mut MAIN_THREAD_ID: u32 = 0;
 
+#[cfg(feature = "native")]
+fn native(config: &Config) -> Result<Arc<Input>, Box<dyn StdError>> {
+    let conn = Arc::new(Supervisor::open(&config.libvirt.uri)?);
+    supervisor::supervise(Arc::downgrade(&conn));
+    Ok(NativeInput::with_hotplug(conn, config.libvirt.hotplug))
+}
+#[cfg(feature = "remote")]
+fn native(config: &Config) -> Result<Arc<Input>, Box<dyn StdError>> {
+    Ok(RemoteInput::new(RemoteConnection::open(&config.libvirt.uri)?, config.libvirt.hotplug))
+}
+#[cfg(not(any(feature = "native", feature = "remote")))]
+fn native(_config: &Config) -> Result<Arc<Input>, Box<dyn StdError>> {
+    Err(Box::new(InputError::Disabled("native")))
+}
+
 pub fn run(config: &Config) -> Result<(), Box<dyn StdError>> {
     unsafe {
         MAIN_THREAD_ID = win::get_current_thread_id();
@@ -47,15 +68,16 @@ pub fn run(config: &Config) -> Result<(), Box<dyn StdError>> {
 
     let input = if config.native {
         info!("native backend, opening connection to libvirt...");
-        NativeInput::new(Connection::open(&config.libvirt.uri)?)
+        native(config)?
     } else {
         info!("http backend, creating client...");
         HttpInput::new(reqwest::Client::new(), &config.http.url)
     };
 
-    let mut devices: Vec<Box<Device>> = Vec::with_capacity(config.devices.len());
+    // built again for every switch so it looks the domain up on whatever connection the backend has by then,
+    // this one just checks the devices up front
+    input.group(&config.domain, &config.devices)?;
     for device in &config.devices {
-        devices.push(input.device(&config.domain, device)?);
         info!("configured evdev '{}'", device);
     }
 
@@ -68,21 +90,10 @@ pub fn run(config: &Config) -> Result<(), Box<dyn StdError>> {
         }
 
         if hotkey.matches(&msg) {
-            for device in &mut devices {
-                match device.toggle() {
-                    Ok(a) => if !a {
-                        info!("attached device at '{}' to domain '{}'", device.evdev(), device.domain());
-                    } else {
-                        info!("detached device at '{}' from domain '{}'", device.evdev(), device.domain());
-                    }
-                    Err(e) => {
-                        error!("failed to toggle device at '{}' state: {}", device.evdev(), e);
-                        break;
-                    }
-                }
-
-                // sleep for a bit or we'll end up with keys stuck down
-                thread::sleep(Duration::from_millis(300));
+            match input.group(&config.domain, &config.devices).and_then(|g| g.switch(Target::Toggle)) {
+                Ok(true) => info!("attached {} devices to domain '{}'", config.devices.len(), config.domain),
+                Ok(false) => info!("detached {} devices from domain '{}'", config.devices.len(), config.domain),
+                Err(e) => error!("failed to switch devices: {}", e),
             }
         }
     }
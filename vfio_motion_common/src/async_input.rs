@@ -0,0 +1,183 @@
+//! Non-blocking versions of the `input` traits.
+//!
+//! This is thread offload only, not asynchronous I/O: every call runs the blocking backend on a `CpuPool`
+//! thread and the future resolves once it returns, so each call in flight ties up one pool thread for as long
+//! as libvirt or the server takes to answer. Size the pool for the number of concurrent calls, not CPUs.
+//! (libvirt has no async API, and reqwest's async client is still behind `unstable` in 0.8.)
+use std::sync::Arc;
+
+use ::futures::{future, Future};
+use ::futures_cpupool::CpuPool;
+#[cfg(feature = "http")]
+use ::reqwest;
+
+#[cfg(all(feature = "native", not(feature = "remote")))]
+use ::supervisor::{self, Supervisor};
+#[cfg(feature = "remote")]
+use ::remote::{RemoteConnection, RemoteInput};
+use ::evdev::EvdevInfo;
+use ::input::{Error, Status, Hotplug, Input, Domains, Device};
+#[cfg(all(feature = "native", not(feature = "remote")))]
+use ::input::NativeInput;
+#[cfg(feature = "http")]
+use ::input::HttpInput;
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use ::qmp::QmpInput;
+
+pub type InputFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+pub trait AsyncInput: Send + Sync {
+    fn domains(&self) -> Arc<AsyncDomains>;
+    fn device(&self, domain: &str, evdev: &str) -> InputFuture<Arc<AsyncDevice>>;
+    fn evdevs(&self) -> InputFuture<Vec<EvdevInfo>>;
+    fn move_devices(&self, from: &str, to: &str, evdevs: &[String]) -> InputFuture<()>;
+}
+
+pub trait AsyncDomains: Send + Sync {
+    fn list(&self) -> InputFuture<Vec<String>>;
+}
+
+pub trait AsyncDevice: Send + Sync {
+    fn evdev(&self) -> &str;
+    fn domain(&self) -> &str;
+
+    /// Never fails, errors are reported as `Status::Unknown`
+    fn status(&self) -> InputFuture<Status>;
+
+    fn attach(&self) -> InputFuture<()>;
+    fn detach(&self) -> InputFuture<()>;
+    fn toggle(&self) -> InputFuture<bool>;
+}
+
+/// Open a native (libvirt) backend without blocking, the connection is opened on `pool` and reconnected for as
+/// long as the backend is around
+#[cfg(all(feature = "native", not(feature = "remote")))]
+pub fn open_native(uri: &str, hotplug: Hotplug, pool: CpuPool) -> InputFuture<Arc<AsyncInput>> {
+    let (uri, task_pool) = (uri.to_owned(), pool.clone());
+    Box::new(pool.spawn_fn(move || -> Result<Arc<AsyncInput>, Error> {
+        let conn = Arc::new(Supervisor::open(&uri)?);
+        supervisor::supervise(Arc::downgrade(&conn));
+        Ok(PooledInput::new(NativeInput::with_hotplug(conn, hotplug), task_pool))
+    }))
+}
+/// Open a native backend over libvirt's remote protocol without linking libvirt, see `remote::RemoteInput`
+#[cfg(feature = "remote")]
+pub fn open_native(uri: &str, hotplug: Hotplug, pool: CpuPool) -> InputFuture<Arc<AsyncInput>> {
+    let (uri, task_pool) = (uri.to_owned(), pool.clone());
+    Box::new(pool.spawn_fn(move || -> Result<Arc<AsyncInput>, Error> {
+        let conn = RemoteConnection::open(&uri)?;
+        Ok(PooledInput::new(RemoteInput::new(conn, hotplug), task_pool))
+    }))
+}
+#[cfg(not(any(feature = "native", feature = "remote")))]
+pub fn open_native(_uri: &str, _hotplug: Hotplug, _pool: CpuPool) -> InputFuture<Arc<AsyncInput>> {
+    Box::new(future::err(Error::Disabled("native")))
+}
+/// A direct QMP backend, see `qmp::QmpInput`
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+pub fn qmp(input: QmpInput, pool: CpuPool) -> Arc<AsyncInput> {
+    PooledInput::new(Arc::new(input), pool)
+}
+#[cfg(feature = "http")]
+pub fn http(client: reqwest::Client, host: &str, pool: CpuPool) -> Arc<AsyncInput> {
+    PooledInput::new(HttpInput::new(client, host), pool)
+}
+
+/// Runs any blocking `Input` on a thread pool
+#[derive(Clone)]
+pub struct PooledInput {
+    input: Arc<Input>,
+    pool: CpuPool,
+}
+impl PooledInput {
+    pub fn new(input: Arc<Input>, pool: CpuPool) -> Arc<AsyncInput> {
+        Arc::new(PooledInput {
+            input,
+            pool,
+        })
+    }
+}
+impl AsyncInput for PooledInput {
+    fn domains(&self) -> Arc<AsyncDomains> {
+        Arc::new(PooledDomains {
+            domains: self.input.domains(),
+            pool: self.pool.clone(),
+        })
+    }
+    fn device(&self, domain: &str, evdev: &str) -> InputFuture<Arc<AsyncDevice>> {
+        let (input, pool) = (self.input.clone(), self.pool.clone());
+        let (domain, evdev) = (domain.to_owned(), evdev.to_owned());
+        Box::new(self.pool.spawn_fn(move || -> Result<Arc<AsyncDevice>, Error> {
+            Ok(Arc::new(PooledDevice {
+                device: input.device(&domain, &evdev)?,
+                pool,
+            }))
+        }))
+    }
+    fn evdevs(&self) -> InputFuture<Vec<EvdevInfo>> {
+        let input = self.input.clone();
+        Box::new(self.pool.spawn_fn(move || input.evdevs()))
+    }
+    fn move_devices(&self, from: &str, to: &str, evdevs: &[String]) -> InputFuture<()> {
+        let input = self.input.clone();
+        let (from, to, evdevs) = (from.to_owned(), to.to_owned(), evdevs.to_vec());
+        Box::new(self.pool.spawn_fn(move || input.move_devices(&from, &to, &evdevs)))
+    }
+}
+
+#[derive(Clone)]
+pub struct PooledDomains {
+    domains: Arc<Domains>,
+    pool: CpuPool,
+}
+impl AsyncDomains for PooledDomains {
+    fn list(&self) -> InputFuture<Vec<String>> {
+        let domains = self.domains.clone();
+        Box::new(self.pool.spawn_fn(move || domains.list()))
+    }
+}
+
+#[derive(Clone)]
+pub struct PooledDevice {
+    device: Arc<Device>,
+    pool: CpuPool,
+}
+impl PooledDevice {
+    pub fn new(device: Arc<Device>, pool: CpuPool) -> Arc<AsyncDevice> {
+        Arc::new(PooledDevice {
+            device,
+            pool,
+        })
+    }
+
+    fn run<T, F>(&self, op: F) -> InputFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Device) -> Result<T, Error> + Send + 'static,
+    {
+        let device = self.device.clone();
+        Box::new(self.pool.spawn_fn(move || op(&*device)))
+    }
+}
+impl AsyncDevice for PooledDevice {
+    fn evdev(&self) -> &str {
+        self.device.evdev()
+    }
+    fn domain(&self) -> &str {
+        self.device.domain()
+    }
+
+    fn status(&self) -> InputFuture<Status> {
+        Box::new(self.run(|d| Ok(d.status())).or_else(|e| future::ok(Status::Unknown(e))))
+    }
+
+    fn attach(&self) -> InputFuture<()> {
+        self.run(|d| d.attach())
+    }
+    fn detach(&self) -> InputFuture<()> {
+        self.run(|d| d.detach())
+    }
+    fn toggle(&self) -> InputFuture<bool> {
+        self.run(|d| d.toggle())
+    }
+}
@@ -0,0 +1,433 @@
+use std::fmt;
+use std::str::FromStr;
+
+use ::serde::de::{self, Deserialize, Deserializer};
+use ::xml::reader::{EventReader, XmlEvent};
+use ::xml::writer::{EmitterConfig, XmlEvent as WriterEvent};
+use ::xml::attribute::OwnedAttribute;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Xml(err: ::xml::reader::Error) {
+            from()
+            display("failed to parse xml: {}", err)
+        }
+        XmlWrite(err: ::xml::writer::Error) {
+            from()
+            display("failed to write xml: {}", err)
+        }
+        NotDomain(root: String) {
+            display("expected <domain> root element, found <{}>", root)
+        }
+        NotInput(root: String) {
+            display("expected <input> root element in template, found <{}>", root)
+        }
+        Placeholder(name: String) {
+            display("unknown placeholder {{{}}} in template", name)
+        }
+        NoEvdev {
+            description("template has no {evdev} placeholder")
+        }
+        BadAddress(addr: String) {
+            display("invalid PCI address {:?}, expected dddd:bb:ss.f", addr)
+        }
+        BadUsb(spec: String) {
+            display("invalid USB device {:?}, expected vendor:product (hex) or bus.device", spec)
+        }
+    }
+}
+
+fn attr<'a>(attrs: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attrs.iter()
+        .find(|a| a.name.prefix.is_none() && a.name.local_name == name)
+        .map(|a| a.value.as_str())
+}
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\'' => escaped.push_str("&apos;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 32-bit FNV-1a of `value` in hex, stable across Rust versions unlike `DefaultHasher` since it ends up in
+/// domain definitions
+pub fn short_hash(value: &str) -> String {
+    let hash = value.bytes().fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193));
+    format!("{:08x}", hash)
+}
+
+pub const ALIAS_PREFIX: &'static str = "ua-vfio-motion-";
+/// User alias (the `ua-` prefix is required by libvirt) used to recognise devices we attached. Sanitising
+/// makes e.g. `a.b` and `a-b` look the same, so a hash of the full path keeps them apart.
+pub fn alias(evdev: &str) -> String {
+    let name: String = evdev.trim_left_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '-' })
+        .collect();
+    format!("{}{}-{}", ALIAS_PREFIX, name, short_hash(evdev))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciAddress {
+    pub domain: u16,
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+}
+impl PciAddress {
+    pub fn to_xml(&self) -> String {
+        format!("<address type='pci' domain='{:#06x}' bus='{:#04x}' slot='{:#04x}' function='{:#x}'/>",
+                self.domain, self.bus, self.slot, self.function)
+    }
+}
+impl FromStr for PciAddress {
+    type Err = Error;
+    fn from_str(addr: &str) -> Result<Self, Error> {
+        let bad = || Error::BadAddress(addr.to_owned());
+        let (rest, function) = match addr.rfind('.') {
+            Some(i) => (&addr[..i], &addr[i + 1..]),
+            None => return Err(bad())
+        };
+        let parts: Vec<&str> = rest.split(':').collect();
+        if parts.len() != 3 {
+            return Err(bad());
+        }
+
+        Ok(PciAddress {
+            domain: u16::from_str_radix(parts[0], 16).map_err(|_| bad())?,
+            bus: u8::from_str_radix(parts[1], 16).map_err(|_| bad())?,
+            slot: u8::from_str_radix(parts[2], 16).map_err(|_| bad())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| bad())?,
+        })
+    }
+}
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{:x}", self.domain, self.bus, self.slot, self.function)
+    }
+}
+impl<'de> Deserialize<'de> for PciAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// How a USB host device is picked out, by what it is or where it's plugged in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbSource {
+    Id { vendor: u16, product: u16 },
+    Address { bus: u16, device: u16 },
+}
+impl UsbSource {
+    /// `<hostdev>` for the device, tagged with `alias`
+    pub fn to_xml(&self, alias: &str) -> String {
+        let source = match *self {
+            UsbSource::Id { vendor, product } => format!("<vendor id='{:#06x}'/><product id='{:#06x}'/>", vendor, product),
+            UsbSource::Address { bus, device } => format!("<address bus='{}' device='{}'/>", bus, device),
+        };
+        format!("<hostdev mode='subsystem' type='usb' managed='yes'>\n  <source>{}</source>\n  <alias name='{}'/>\n</hostdev>",
+                source, escape(alias))
+    }
+}
+impl FromStr for UsbSource {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Error> {
+        let bad = || Error::BadUsb(spec.to_owned());
+        if let Some(i) = spec.find(':') {
+            return Ok(UsbSource::Id {
+                vendor: u16::from_str_radix(&spec[..i], 16).map_err(|_| bad())?,
+                product: u16::from_str_radix(&spec[i + 1..], 16).map_err(|_| bad())?,
+            });
+        }
+        if let Some(i) = spec.find('.') {
+            return Ok(UsbSource::Address {
+                bus: spec[..i].parse().map_err(|_| bad())?,
+                device: spec[i + 1..].parse().map_err(|_| bad())?,
+            });
+        }
+        Err(bad())
+    }
+}
+impl fmt::Display for UsbSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UsbSource::Id { vendor, product } => write!(f, "{:04x}:{:04x}", vendor, product),
+            UsbSource::Address { bus, device } => write!(f, "{}.{}", bus, device),
+        }
+    }
+}
+
+const PLACEHOLDERS: [&'static str; 3] = [ "evdev", "alias", "address" ];
+/// An `<input>` element with `{evdev}`, `{alias}` and `{address}` placeholders, checked when it's created
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(String);
+impl Template {
+    pub fn new(xml: &str) -> Result<Template, Error> {
+        let mut rest = xml;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').map_or(rest.len(), |e| start + e);
+            let name = &rest[start + 1..end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(Error::Placeholder(name.to_owned()));
+            }
+            rest = &rest[(end + 1).min(rest.len())..];
+        }
+        if !xml.contains("{evdev}") {
+            return Err(Error::NoEvdev);
+        }
+
+        // every device we attach must have an alias
+        let template = match xml.contains("{alias}") {
+            true => Template(xml.to_owned()),
+            false => Template(with_alias(xml)?)
+        };
+        let example = PciAddress { domain: 0, bus: 0, slot: 0x10, function: 0 };
+        let rendered = template.render("/dev/input/event0", &alias("/dev/input/event0"), Some(&example));
+        let mut root = None;
+        for event in EventReader::from_str(&rendered) {
+            if let XmlEvent::StartElement { name, .. } = event? {
+                root.get_or_insert(name.local_name);
+            }
+        }
+        match root {
+            Some(ref r) if r == "input" => Ok(template),
+            r => Err(Error::NotInput(r.unwrap_or_default()))
+        }
+    }
+
+    /// `alias` comes from the name the device was asked for, which may not be the node `evdev` resolved to
+    pub fn render(&self, evdev: &str, alias: &str, address: Option<&PciAddress>) -> String {
+        self.0
+            .replace("{evdev}", &escape(evdev))
+            .replace("{alias}", &escape(alias))
+            .replace("{address}", &address.map(|a| a.to_xml()).unwrap_or_default())
+    }
+}
+/// Add `<alias name='{alias}'/>` as the last child of the root element, which may well be self-closing
+fn with_alias(xml: &str) -> Result<String, Error> {
+    let mut out = Vec::new();
+    {
+        let mut writer = EmitterConfig::new()
+            .write_document_declaration(false)
+            .create_writer(&mut out);
+        let mut depth = 0;
+        for event in EventReader::from_str(xml) {
+            let event = event?;
+            match event {
+                XmlEvent::StartElement { .. } => depth += 1,
+                XmlEvent::EndElement { .. } => {
+                    depth -= 1;
+                    if depth == 0 {
+                        writer.write(WriterEvent::start_element("alias").attr("name", "{alias}"))?;
+                        writer.write(WriterEvent::end_element())?;
+                    }
+                },
+                _ => {}
+            }
+            if let Some(e) = event.as_writer_event() {
+                writer.write(e)?;
+            }
+        }
+    }
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template(include_str!("attach_detach.xml").to_owned())
+    }
+}
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Template::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputKind {
+    /// `<input type='passthrough'>`, a virtio-input-host device in the guest
+    Passthrough,
+    /// `<input type='evdev'>`, QEMU grabs the evdev and feeds it to the guest's existing input devices
+    Evdev,
+}
+impl Default for InputKind {
+    fn default() -> Self {
+        InputKind::Passthrough
+    }
+}
+
+/// An `<input>` element backed by a host evdev from a domain's `<devices>`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DomainInput {
+    pub kind: InputKind,
+    pub evdev: String,
+    pub bus: Option<String>,
+    pub alias: Option<String>,
+    pub grab_toggle: Option<String>,
+}
+impl DomainInput {
+    /// Whether we attached this input, rather than the admin
+    pub fn managed(&self) -> bool {
+        self.alias.as_ref().map_or(false, |a| a.starts_with(ALIAS_PREFIX))
+    }
+    /// Minimal XML libvirt will match against this input when detaching
+    pub fn to_xml(&self) -> String {
+        match self.kind {
+            InputKind::Passthrough => {
+                let mut xml = String::from("<input type='passthrough'");
+                if let Some(ref bus) = self.bus {
+                    xml.push_str(&format!(" bus='{}'", escape(bus)));
+                }
+                xml.push_str(&format!(">\n  <source evdev='{}'/>\n</input>", escape(&self.evdev)));
+                xml
+            },
+            InputKind::Evdev => format!("<input type='evdev'>\n  <source dev='{}'/>\n</input>", escape(&self.evdev)),
+        }
+    }
+}
+
+/// A USB `<hostdev>` from a domain's `<devices>`, libvirt fills in both the id and address of running domains
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DomainHostdev {
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub bus: Option<u16>,
+    pub device: Option<u16>,
+    pub alias: Option<String>,
+}
+impl DomainHostdev {
+    pub fn matches(&self, source: &UsbSource) -> bool {
+        match *source {
+            UsbSource::Id { vendor, product } => self.vendor == Some(vendor) && self.product == Some(product),
+            UsbSource::Address { bus, device } => self.bus == Some(bus) && self.device == Some(device),
+        }
+    }
+}
+
+fn hex_attr(attrs: &[OwnedAttribute], name: &str) -> Option<u16> {
+    attr(attrs, name).and_then(|v| u16::from_str_radix(v.trim_left_matches("0x"), 16).ok())
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DomainXml {
+    pub name: String,
+    pub inputs: Vec<DomainInput>,
+    pub hostdevs: Vec<DomainHostdev>,
+}
+impl DomainXml {
+    pub fn parse(xml: &str) -> Result<DomainXml, Error> {
+        let mut dom = DomainXml::default();
+        let mut path: Vec<String> = Vec::new();
+        let mut input: Option<DomainInput> = None;
+        let mut hostdev: Option<DomainHostdev> = None;
+
+        for event in EventReader::from_str(xml) {
+            match event? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let name = name.local_name;
+                    if path.is_empty() && name != "domain" {
+                        return Err(Error::NotDomain(name));
+                    }
+
+                    match (path.len(), name.as_str()) {
+                        // <domain><devices><input>
+                        (2, "input") if path[1] == "devices" => {
+                            let kind = match attr(&attributes, "type") {
+                                Some("passthrough") => Some(InputKind::Passthrough),
+                                Some("evdev") => Some(InputKind::Evdev),
+                                _ => None
+                            };
+                            input = kind.map(|kind| DomainInput {
+                                kind,
+                                bus: attr(&attributes, "bus").map(String::from),
+                                ..DomainInput::default()
+                            });
+                        },
+                        // <domain><devices><hostdev>
+                        (2, "hostdev") if path[1] == "devices" => {
+                            if attr(&attributes, "mode") == Some("subsystem") && attr(&attributes, "type") == Some("usb") {
+                                hostdev = Some(DomainHostdev::default());
+                            }
+                        },
+                        (4, "vendor") if path[3] == "source" => if let Some(ref mut h) = hostdev {
+                            h.vendor = hex_attr(&attributes, "id");
+                        },
+                        (4, "product") if path[3] == "source" => if let Some(ref mut h) = hostdev {
+                            h.product = hex_attr(&attributes, "id");
+                        },
+                        (4, "address") if path[3] == "source" => if let Some(ref mut h) = hostdev {
+                            h.bus = attr(&attributes, "bus").and_then(|b| b.parse().ok());
+                            h.device = attr(&attributes, "device").and_then(|d| d.parse().ok());
+                        },
+                        (3, "alias") if hostdev.is_some() => if let Some(ref mut h) = hostdev {
+                            h.alias = attr(&attributes, "name").map(String::from);
+                        },
+                        (3, "source") => if let Some(ref mut i) = input {
+                            let evdev = match i.kind {
+                                InputKind::Passthrough => attr(&attributes, "evdev"),
+                                InputKind::Evdev => attr(&attributes, "dev"),
+                            };
+                            if let Some(evdev) = evdev {
+                                i.evdev = evdev.to_owned();
+                            }
+                            i.grab_toggle = attr(&attributes, "grabToggle").map(String::from);
+                        },
+                        (3, "alias") => if let Some(ref mut i) = input {
+                            i.alias = attr(&attributes, "name").map(String::from);
+                        },
+                        _ => {}
+                    }
+                    path.push(name);
+                },
+                XmlEvent::EndElement { .. } => {
+                    path.pop();
+                    if path.len() == 2 {
+                        if let Some(i) = input.take() {
+                            dom.inputs.push(i);
+                        }
+                        if let Some(h) = hostdev.take() {
+                            dom.hostdevs.push(h);
+                        }
+                    }
+                },
+                XmlEvent::Characters(text) => if path.len() == 2 && path[1] == "name" {
+                    dom.name = text;
+                },
+                _ => {}
+            }
+        }
+
+        Ok(dom)
+    }
+
+    /// Find the input we attached with `alias`
+    pub fn managed(&self, alias: &str) -> Option<&DomainInput> {
+        self.inputs.iter().find(|i| i.alias.as_ref().map(|a| a.as_str()) == Some(alias))
+    }
+    /// Find the USB hostdev we attached with `alias`
+    pub fn managed_hostdev(&self, alias: &str) -> Option<&DomainHostdev> {
+        self.hostdevs.iter().find(|h| h.alias.as_ref().map(|a| a.as_str()) == Some(alias))
+    }
+    /// Find a USB hostdev for `source`
+    pub fn usb(&self, source: &UsbSource) -> Option<&DomainHostdev> {
+        self.hostdevs.iter().find(|h| h.matches(source))
+    }
+    /// Find the passthrough input whose source is exactly `evdev`
+    pub fn passthrough(&self, evdev: &str) -> Option<&DomainInput> {
+        self.input(InputKind::Passthrough, evdev)
+    }
+    /// Find the input of type `kind` whose source is exactly `evdev`
+    pub fn input(&self, kind: InputKind, evdev: &str) -> Option<&DomainInput> {
+        self.inputs.iter().find(|i| i.kind == kind && i.evdev == evdev)
+    }
+}
@@ -0,0 +1,487 @@
+//! Discovery and validation of the host's evdev nodes.
+use std::io;
+#[cfg(target_os = "linux")]
+use std::fs::{self, File};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+
+#[cfg(target_os = "linux")]
+use ::libc::c_int;
+
+use ::domain_xml::UsbSource;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error) {
+            from()
+            display("evdev I/O error: {}", err)
+        }
+        NotFound(evdev: String) {
+            display("evdev {:?} does not exist", evdev)
+        }
+        OutsideRoot(evdev: String, root: String) {
+            display("evdev {:?} is not under {:?}", evdev, root)
+        }
+        NotCharDevice(evdev: String) {
+            display("{:?} is not a character device", evdev)
+        }
+        NotEvdev(evdev: String) {
+            display("{:?} is not an evdev", evdev)
+        }
+        NotAllowed(evdev: String) {
+            display("evdev {:?} is not in the allowlist", evdev)
+        }
+        UsbNotAllowed(device: String) {
+            display("USB device {} is not in the allowlist", device)
+        }
+        Pattern(err: ::glob::PatternError) {
+            from()
+            display("bad device glob: {}", err)
+        }
+        NoMatch(spec: String) {
+            display("no evdevs match {:?}", spec)
+        }
+        NeedsHost(spec: String) {
+            display("{:?} can only be resolved on the host that owns the evdevs", spec)
+        }
+    }
+}
+
+pub const INPUT_DIR: &'static str = "/dev/input";
+#[cfg(target_os = "linux")]
+const SYSFS_INPUT: &'static str = "/sys/class/input";
+
+// from linux/input.h
+#[cfg(target_os = "linux")]
+mod sys {
+    use ::libc::c_int;
+
+    ioctl_read!(eviocgversion, b'E', 0x01, c_int);
+    // EVIOCGBIT(ev, len), one per event type we care about
+    ioctl_read_buf!(eviocgbit_ev, b'E', 0x20, u8);
+    ioctl_read_buf!(eviocgbit_key, b'E', 0x20 + EV_KEY, u8);
+    ioctl_read_buf!(eviocgbit_rel, b'E', 0x20 + EV_REL, u8);
+    ioctl_read_buf!(eviocgbit_abs, b'E', 0x20 + EV_ABS, u8);
+
+    pub const EV_MAX: usize = 0x1f;
+    pub const KEY_MAX: usize = 0x2ff;
+    pub const REL_MAX: usize = 0x0f;
+    pub const ABS_MAX: usize = 0x3f;
+
+    pub const EV_KEY: usize = 0x01;
+    pub const EV_REL: usize = 0x02;
+    pub const EV_ABS: usize = 0x03;
+    pub const EV_SW: usize = 0x05;
+    pub const EV_LED: usize = 0x11;
+
+    pub const KEY_Q: usize = 16;
+    pub const KEY_A: usize = 30;
+    pub const KEY_SPACE: usize = 57;
+    pub const BTN_LEFT: usize = 0x110;
+    pub const BTN_JOYSTICK: usize = 0x120;
+    pub const BTN_GAMEPAD: usize = 0x130;
+    pub const BTN_TOUCH: usize = 0x14a;
+    pub const REL_X: usize = 0x00;
+    pub const REL_Y: usize = 0x01;
+    pub const ABS_X: usize = 0x00;
+    pub const ABS_Y: usize = 0x01;
+}
+#[cfg(target_os = "linux")]
+use self::sys::*;
+
+/// How a user names a physical device in the config or a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Specifier {
+    /// A path to an evdev, bare names are taken to be in `/dev/input/by-id`
+    Path(String),
+    /// A glob over evdev paths, relative to `/dev/input/by-id` if it has no `/`
+    Glob(String),
+    /// `vendor:product` in hex, like `lsusb` shows
+    UsbId { vendor: String, product: String },
+}
+impl Specifier {
+    pub fn parse(spec: &str) -> Specifier {
+        let is_id = |s: &str| s.len() == 4 && s.chars().all(|c| c.is_digit(16));
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() == 2 && is_id(parts[0]) && is_id(parts[1]) {
+            return Specifier::UsbId {
+                vendor: parts[0].to_lowercase(),
+                product: parts[1].to_lowercase(),
+            };
+        }
+
+        let path = if spec.contains('/') {
+            spec.to_owned()
+        } else {
+            format!("{}/by-id/{}", INPUT_DIR, spec)
+        };
+        if spec.contains(|c| c == '*' || c == '?' || c == '[') {
+            Specifier::Glob(path)
+        } else {
+            Specifier::Path(path)
+        }
+    }
+}
+
+/// Find every evdev node of the physical device(s) that `spec` names
+#[cfg(target_os = "linux")]
+pub fn resolve(spec: &str) -> Result<Vec<String>, Error> {
+    let evdevs = list()?;
+    let matched: Vec<&EvdevInfo> = match Specifier::parse(spec) {
+        Specifier::Path(path) => {
+            let node = match fs::canonicalize(&path) {
+                Ok(n) => n,
+                // let `EvdevPolicy` explain what's wrong with it
+                Err(_) => return Ok(vec![path])
+            };
+            match evdevs.iter().find(|e| Path::new(&e.path) == node.as_path()) {
+                Some(e) => vec![e],
+                None => return Ok(vec![path])
+            }
+        },
+        Specifier::Glob(pattern) => {
+            let nodes = ::glob::glob(&pattern)?
+                .filter_map(|p| p.ok())
+                .filter_map(|p| fs::canonicalize(p).ok())
+                .collect::<Vec<_>>();
+            evdevs.iter().filter(|e| nodes.iter().any(|n| Path::new(&e.path) == n.as_path())).collect()
+        },
+        Specifier::UsbId { vendor, product } => evdevs.iter()
+            .filter(|e| e.vendor.as_ref().map(|v| v.to_lowercase()) == Some(vendor.clone()) &&
+                        e.product.as_ref().map(|p| p.to_lowercase()) == Some(product.clone()))
+            .collect(),
+    };
+    if matched.is_empty() {
+        return Err(Error::NoMatch(spec.to_owned()));
+    }
+
+    let nodes = evdevs.iter()
+        .filter(|e| matched.iter().any(|m| m.path == e.path || (m.physical.is_some() && m.physical == e.physical)))
+        .map(|e| e.preferred_path().to_owned())
+        .collect::<Vec<_>>();
+    debug!("{:?} resolved to {:?}", spec, nodes);
+    Ok(nodes)
+}
+/// Without sysfs only paths can be resolved, to the single node they name on the host. Globs and
+/// `vendor:product` need the host's evdevs, go through the HTTP backend for those.
+#[cfg(not(target_os = "linux"))]
+pub fn resolve(spec: &str) -> Result<Vec<String>, Error> {
+    match Specifier::parse(spec) {
+        Specifier::Path(path) => Ok(vec![path]),
+        _ => Err(Error::NeedsHost(spec.to_owned()))
+    }
+}
+
+fn default_root() -> String {
+    INPUT_DIR.to_owned()
+}
+/// Which paths may be passed through to a domain as evdevs
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvdevPolicy {
+    /// evdevs must resolve to somewhere under this directory
+    #[serde(default = "default_root")]
+    pub root: String,
+    /// If not empty, evdevs must resolve to one of these
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// USB devices that may be passed through whole, as `vendor:product` or `bus.device`. Empty allows none,
+    /// unlike evdevs there's nothing to stop a whole USB device being the host's keyboard or a disk.
+    #[serde(default)]
+    pub usb: Vec<String>,
+}
+impl Default for EvdevPolicy {
+    fn default() -> Self {
+        EvdevPolicy {
+            root: default_root(),
+            allow: Vec::new(),
+            usb: Vec::new(),
+        }
+    }
+}
+impl EvdevPolicy {
+    /// Whether the whole USB device `source` may be passed through, an allowlist entry can name it either way
+    pub fn check_usb(&self, source: &UsbSource) -> Result<(), Error> {
+        let ids = usb_ids(source);
+        let allowed = self.usb.iter()
+            .filter_map(|a| a.parse::<UsbSource>().ok())
+            .any(|a| a == *source || Some(a) == ids);
+        if !allowed {
+            return Err(Error::UsbNotAllowed(source.to_string()));
+        }
+        Ok(())
+    }
+}
+#[cfg(target_os = "linux")]
+impl EvdevPolicy {
+    /// Returns the node `evdev` resolves to, which is what should be handed to QEMU so a symlink swapped after
+    /// the check can't point it somewhere else
+    pub fn check(&self, evdev: &str) -> Result<PathBuf, Error> {
+        let path = fs::canonicalize(evdev).map_err(|_| Error::NotFound(evdev.to_owned()))?;
+        if !path.starts_with(fs::canonicalize(&self.root)?) {
+            return Err(Error::OutsideRoot(evdev.to_owned(), self.root.clone()));
+        }
+        if !fs::metadata(&path)?.file_type().is_char_device() {
+            return Err(Error::NotCharDevice(evdev.to_owned()));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().filter_map(|a| fs::canonicalize(a).ok()).any(|a| a == path) {
+            return Err(Error::NotAllowed(evdev.to_owned()));
+        }
+
+        let version = evdev_version(&File::open(&path)?).map_err(|_| Error::NotEvdev(evdev.to_owned()))?;
+        debug!("evdev {:?} resolves to {:?}, version {:#x}", evdev, path, version);
+        Ok(path)
+    }
+}
+#[cfg(not(target_os = "linux"))]
+impl EvdevPolicy {
+    /// evdevs can only be checked on the host that owns them
+    pub fn check(&self, evdev: &str) -> Result<PathBuf, Error> {
+        Ok(PathBuf::from(evdev))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Keyboard,
+    Buttons,
+    RelativePointer,
+    AbsolutePointer,
+    Touch,
+    Joystick,
+    Switch,
+    Led,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvdevInfo {
+    /// `/dev/input/eventN`
+    pub path: String,
+    pub by_id: Vec<String>,
+    pub by_path: Vec<String>,
+
+    pub name: String,
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub capabilities: Vec<Capability>,
+    /// sysfs path of the HID device (or USB interface) this evdev belongs to, shared by all of its nodes
+    pub physical: Option<String>,
+}
+impl EvdevInfo {
+    /// The most stable path to refer to this device by
+    pub fn preferred_path(&self) -> &str {
+        self.by_id.first()
+            .or(self.by_path.first())
+            .unwrap_or(&self.path)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn test_bit(bits: &[u8], bit: usize) -> bool {
+    bits.get(bit / 8).map_or(false, |b| b & (1 << (bit % 8)) != 0)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn nix_io(err: ::nix::Error) -> io::Error {
+    match err {
+        ::nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Version of the evdev protocol spoken by `file`, fails if it's not an evdev
+#[cfg(target_os = "linux")]
+pub fn evdev_version(file: &File) -> io::Result<c_int> {
+    let mut version: c_int = 0;
+    unsafe {
+        sys::eviocgversion(file.as_raw_fd(), &mut version).map_err(nix_io)?;
+    }
+    Ok(version)
+}
+
+#[cfg(target_os = "linux")]
+pub fn capabilities(file: &File) -> io::Result<Vec<Capability>> {
+    let fd = file.as_raw_fd();
+    let mut ev = [0u8; EV_MAX / 8 + 1];
+    let mut keys = [0u8; KEY_MAX / 8 + 1];
+    let mut rel = [0u8; REL_MAX / 8 + 1];
+    let mut abs = [0u8; ABS_MAX / 8 + 1];
+    unsafe {
+        sys::eviocgbit_ev(fd, &mut ev).map_err(nix_io)?;
+        if test_bit(&ev, EV_KEY) {
+            sys::eviocgbit_key(fd, &mut keys).map_err(nix_io)?;
+        }
+        if test_bit(&ev, EV_REL) {
+            sys::eviocgbit_rel(fd, &mut rel).map_err(nix_io)?;
+        }
+        if test_bit(&ev, EV_ABS) {
+            sys::eviocgbit_abs(fd, &mut abs).map_err(nix_io)?;
+        }
+    }
+
+    let mut caps = Vec::new();
+    if test_bit(&keys, KEY_Q) && test_bit(&keys, KEY_A) && test_bit(&keys, KEY_SPACE) {
+        caps.push(Capability::Keyboard);
+    }
+    if test_bit(&keys, BTN_LEFT) {
+        caps.push(Capability::Buttons);
+    }
+    if test_bit(&rel, REL_X) && test_bit(&rel, REL_Y) {
+        caps.push(Capability::RelativePointer);
+    }
+    if test_bit(&abs, ABS_X) && test_bit(&abs, ABS_Y) {
+        caps.push(if test_bit(&keys, BTN_TOUCH) {
+            Capability::Touch
+        } else {
+            Capability::AbsolutePointer
+        });
+    }
+    if test_bit(&keys, BTN_JOYSTICK) || test_bit(&keys, BTN_GAMEPAD) {
+        caps.push(Capability::Joystick);
+    }
+    if test_bit(&ev, EV_SW) {
+        caps.push(Capability::Switch);
+    }
+    if test_bit(&ev, EV_LED) {
+        caps.push(Capability::Led);
+    }
+    Ok(caps)
+}
+
+/// Map of canonical evdev path to the symlinks in `dir` that point at it
+#[cfg(target_os = "linux")]
+fn aliases(dir: &Path) -> io::Result<HashMap<PathBuf, Vec<String>>> {
+    let mut aliases: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    if !dir.exists() {
+        return Ok(aliases);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let link = entry?.path();
+        if let Ok(target) = fs::canonicalize(&link) {
+            aliases.entry(target).or_insert_with(Vec::new).push(link.to_string_lossy().into_owned());
+        }
+    }
+    for links in aliases.values_mut() {
+        links.sort();
+    }
+    Ok(aliases)
+}
+
+#[cfg(target_os = "linux")]
+fn sysfs_attr(node: &str, attr: &str) -> Option<String> {
+    fs::read_to_string(Path::new(SYSFS_INPUT).join(node).join("device").join(attr))
+        .ok()
+        .map(|v| v.trim().to_owned())
+}
+
+#[cfg(target_os = "linux")]
+const SYSFS_USB: &'static str = "/sys/bus/usb/devices";
+/// `vendor:product` of a USB device, looked up in sysfs if it's given by address
+#[cfg(target_os = "linux")]
+fn usb_ids(source: &UsbSource) -> Option<UsbSource> {
+    let (bus, device) = match *source {
+        UsbSource::Id { .. } => return Some(*source),
+        UsbSource::Address { bus, device } => (bus, device)
+    };
+
+    let read = |dir: &Path, attr: &str| fs::read_to_string(dir.join(attr)).ok().map(|v| v.trim().to_owned());
+    for entry in fs::read_dir(SYSFS_USB).ok()?.filter_map(|e| e.ok()) {
+        let dir = entry.path();
+        if read(&dir, "busnum").and_then(|b| b.parse().ok()) != Some(bus) ||
+            read(&dir, "devnum").and_then(|d| d.parse().ok()) != Some(device) {
+            continue;
+        }
+        return Some(UsbSource::Id {
+            vendor: u16::from_str_radix(&read(&dir, "idVendor")?, 16).ok()?,
+            product: u16::from_str_radix(&read(&dir, "idProduct")?, 16).ok()?,
+        });
+    }
+    None
+}
+#[cfg(not(target_os = "linux"))]
+fn usb_ids(source: &UsbSource) -> Option<UsbSource> {
+    match *source {
+        UsbSource::Id { .. } => Some(*source),
+        UsbSource::Address { .. } => None
+    }
+}
+
+/// Whether a sysfs directory is a HID device (`BBBB:VVVV:PPPP.NNNN`)
+#[cfg(target_os = "linux")]
+fn is_hid_device(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return false
+    };
+    let parts: Vec<&str> = name.split(|c| c == ':' || c == '.').collect();
+    parts.len() == 4 && parts.iter().all(|p| p.len() == 4 && p.chars().all(|c| c.is_digit(16)))
+}
+
+/// The HID device an evdev belongs to, or the USB interface for anything that isn't HID, falling back to the
+/// input device itself. Never anything further up, a wireless receiver or Bluetooth dongle would otherwise
+/// lump every device paired with it together, and virtual devices would all share `/sys/devices/virtual/input`.
+#[cfg(target_os = "linux")]
+fn physical_device(node: &str) -> Option<PathBuf> {
+    let input = fs::canonicalize(Path::new(SYSFS_INPUT).join(node).join("device")).ok()?;
+    let physical = input.ancestors()
+        .take_while(|p| !p.join("idVendor").exists())
+        .find(|p| is_hid_device(p) || p.join("bInterfaceNumber").exists())
+        .unwrap_or(input.as_path())
+        .to_owned();
+    Some(physical)
+}
+
+#[cfg(target_os = "linux")]
+fn event_number(path: &str) -> u32 {
+    path.rsplit("event").next().and_then(|n| n.parse().ok()).unwrap_or(u32::max_value())
+}
+
+/// List every `/dev/input/eventN` on the host
+#[cfg(target_os = "linux")]
+pub fn list() -> Result<Vec<EvdevInfo>, Error> {
+    let input_dir = Path::new(INPUT_DIR);
+    let by_id = aliases(&input_dir.join("by-id"))?;
+    let by_path = aliases(&input_dir.join("by-path"))?;
+
+    let mut evdevs = Vec::new();
+    for entry in fs::read_dir(input_dir)? {
+        let entry = entry?;
+        let node = entry.file_name().to_string_lossy().into_owned();
+        if !node.starts_with("event") {
+            continue;
+        }
+
+        let path = entry.path();
+        let capabilities = match File::open(&path).and_then(|f| capabilities(&f)) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("failed to query capabilities of {:?}: {}", path, e);
+                Vec::new()
+            }
+        };
+
+        evdevs.push(EvdevInfo {
+            path: path.to_string_lossy().into_owned(),
+            by_id: by_id.get(&path).cloned().unwrap_or_default(),
+            by_path: by_path.get(&path).cloned().unwrap_or_default(),
+
+            name: sysfs_attr(&node, "name").unwrap_or_default(),
+            vendor: sysfs_attr(&node, "id/vendor"),
+            product: sysfs_attr(&node, "id/product"),
+            capabilities,
+            physical: physical_device(&node).map(|p| p.to_string_lossy().into_owned()),
+        });
+    }
+
+    evdevs.sort_by_key(|e| event_number(&e.path));
+    Ok(evdevs)
+}
@@ -1,99 +1,768 @@
-#[cfg(target_os = "linux")]
-use std::path::PathBuf;
-use std::fmt;
-
-#[cfg(target_os = "linux")]
-use ::nix::sys::stat::{stat, SFlag};
-use ::serde::de::{self, Deserialize, Deserializer, Visitor, MapAccess};
-use ::virt::domain::{VIR_DOMAIN_AFFECT_LIVE, VIR_DOMAIN_NONE};
-#[cfg(target_os = "windows")]
+use std::sync::Arc;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use ::futures::Future;
+use ::futures_cpupool::{CpuPool, Builder as CpuPoolBuilder};
+
+#[cfg(feature = "http")]
 use ::reqwest;
 
-use ::libvirt::{self, Connection, Domain};
+use ::libvirt::{self, VIR_DOMAIN_AFFECT_LIVE, VIR_DOMAIN_AFFECT_CONFIG, VIR_DOMAIN_NONE, VIR_DOMAIN_XML_INACTIVE};
+#[cfg(feature = "native")]
+use ::libvirt::{Connection, Domain};
+use ::qmp;
+#[cfg(feature = "native")]
+use ::supervisor::Supervisor;
+use ::domain_xml::{self, DomainXml, InputKind, UsbSource, Template, PciAddress};
+use ::evdev::{self, EvdevInfo, EvdevPolicy};
 
 quick_error! {
     #[derive(Debug)]
     pub enum Error {
-        BadEvdev(evdev: String) {
-            display("Invalid evdev {:?}", evdev)
-        }
         BadState(msg: &'static str) {
             description(msg)
         }
-        GlobalConnNotOpen
+        AlreadyAttached(evdev: String) {
+            display("Device {:?} already attached!", evdev)
+        }
+        NotAttached(evdev: String) {
+            display("Device {:?} not attached!", evdev)
+        }
+        Unmanaged(evdev: String) {
+            display("Device {:?} was attached by hand, leaving it alone", evdev)
+        }
+        NoTemplate(name: String) {
+            display("no template named {:?}", name)
+        }
+        Unsupported(what: &'static str) {
+            display("{} is not supported by this backend", what)
+        }
+        Disabled(feature: &'static str) {
+            display("vfio-motion was built without the {:?} feature", feature)
+        }
+        /// A request asked for something the backend can't honour
+        BadRequest(msg: String) {
+            display("bad request: {}", msg)
+        }
+        StatusUnknown(msg: String) {
+            display("device status unknown: {}", msg)
+        }
+        Evdev(err: evdev::Error) {
+            from()
+            display("{}", err)
+        }
+        DomainXml(err: domain_xml::Error) {
+            from()
+            display("{}", err)
+        }
+        #[cfg(feature = "native")]
         Libvirt(err: libvirt::Error) {
+            display("{}", err)
+        }
+        Qmp(err: qmp::Error) {
             from()
             display("{}", err)
         }
+        #[cfg(feature = "native")]
         Virt(err: ::virt::error::Error) {
-            from()
             display("{}", err)
         }
         Reqwest(err: String) {
             from()
             display("http error: {}", err)
         }
+        HttpDecode(err: String) {
+            display("invalid response from server: {}", err)
+        }
+        /// `stranded` as reported by the server, see `Error::stranded()`
+        Remote(kind: ErrorKind, msg: String, stranded: Vec<String>) {
+            display("{}", msg)
+        }
+        GroupSwitch { evdev: String, err: Box<Error>, stranded: Vec<String> } {
+            display("failed to switch device {:?}: {}", evdev, err)
+        }
+        Move { to: String, err: Box<Error>, restored: bool } {
+            display("failed to move devices to '{}': {}", to, err)
+        }
+    }
+}
+// `from()` isn't used for these since quick_error doesn't carry `#[cfg]` over to the `From` impls
+#[cfg(feature = "native")]
+impl From<libvirt::Error> for Error {
+    fn from(err: libvirt::Error) -> Error {
+        Error::Libvirt(err)
+    }
+}
+#[cfg(feature = "native")]
+impl From<::virt::error::Error> for Error {
+    fn from(err: ::virt::error::Error) -> Error {
+        Error::Virt(err)
+    }
+}
+
+/// Broad class of an `Error`, stable across libvirt versions and locales
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    BadRequest,
+    InvalidDevice,
+    Busy,
+    Missing,
+    NoDomain,
+    NotRunning,
+    PermissionDenied,
+    ConnectionLost,
+    Monitor,
+    Unsupported,
+    Other,
+}
+impl ErrorKind {
+    #[cfg(feature = "native")]
+    pub fn from_virt(err: &::virt::error::Error) -> ErrorKind {
+        ErrorKind::from_code(err.code, err.domain)
+    }
+    /// From a libvirt `virErrorNumber` and `virErrorDomain`, never the (translated) message
+    pub fn from_code(code: i32, domain: i32) -> ErrorKind {
+        match code {
+            libvirt::VIR_ERR_RESOURCE_BUSY => ErrorKind::Busy,
+            libvirt::VIR_ERR_NO_DEVICE | libvirt::VIR_ERR_DEVICE_MISSING => ErrorKind::Missing,
+            libvirt::VIR_ERR_NO_DOMAIN => ErrorKind::NoDomain,
+            // libvirt reports operations on an inactive domain as invalid
+            libvirt::VIR_ERR_OPERATION_INVALID => ErrorKind::NotRunning,
+            libvirt::VIR_ERR_OPERATION_DENIED | libvirt::VIR_ERR_AUTH_FAILED | libvirt::VIR_ERR_AUTH_CANCELLED |
+                libvirt::VIR_ERR_ACCESS_DENIED | libvirt::VIR_ERR_AUTH_UNAVAILABLE => ErrorKind::PermissionDenied,
+            libvirt::VIR_ERR_NO_CONNECT | libvirt::VIR_ERR_INVALID_CONN | libvirt::VIR_ERR_RPC => ErrorKind::ConnectionLost,
+            libvirt::VIR_ERR_SYSTEM_ERROR
+                if domain == libvirt::VIR_FROM_RPC || domain == libvirt::VIR_FROM_REMOTE => ErrorKind::ConnectionLost,
+            libvirt::VIR_ERR_INTERNAL_ERROR | libvirt::VIR_ERR_OPERATION_FAILED
+                if domain == libvirt::VIR_FROM_QEMU => ErrorKind::Monitor,
+            _ => ErrorKind::Other
+        }
+    }
+    /// From a QMP error class, QEMU reports most failures (including an evdev another guest has grabbed) as
+    /// `GenericError` which says nothing more
+    pub fn from_qmp(err: &qmp::QmpError) -> ErrorKind {
+        match err.class.as_str() {
+            "DeviceNotFound" | "DeviceNotActive" => ErrorKind::Missing,
+            "CommandNotFound" => ErrorKind::Unsupported,
+            _ => ErrorKind::Other
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::AlreadyAttached(_) | Error::Unmanaged(_) => ErrorKind::Busy,
+            Error::NotAttached(_) => ErrorKind::Missing,
+            Error::Unsupported(_) | Error::Disabled(_) => ErrorKind::Unsupported,
+            Error::BadRequest(_) => ErrorKind::BadRequest,
+            Error::Evdev(evdev::Error::NotFound(_)) | Error::Evdev(evdev::Error::NotCharDevice(_)) |
+                Error::Evdev(evdev::Error::NotEvdev(_)) => ErrorKind::InvalidDevice,
+            Error::Evdev(evdev::Error::NeedsHost(_)) => ErrorKind::Unsupported,
+            Error::DomainXml(domain_xml::Error::BadUsb(_)) => ErrorKind::InvalidDevice,
+            Error::Evdev(evdev::Error::OutsideRoot(..)) | Error::Evdev(evdev::Error::NotAllowed(_)) |
+                Error::Evdev(evdev::Error::UsbNotAllowed(_)) => ErrorKind::PermissionDenied,
+            Error::Evdev(evdev::Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::PermissionDenied =>
+                ErrorKind::PermissionDenied,
+            #[cfg(feature = "native")]
+            Error::Libvirt(libvirt::Error::Virt(ref e)) | Error::Virt(ref e) => ErrorKind::from_virt(e),
+            #[cfg(feature = "native")]
+            Error::Libvirt(libvirt::Error::QemuMonitor(ref e)) => ErrorKind::from_qmp(e),
+            #[cfg(feature = "native")]
+            Error::Libvirt(libvirt::Error::EmptyReply) => ErrorKind::Monitor,
+            Error::Qmp(qmp::Error::Command(ref e)) => ErrorKind::from_qmp(e),
+            Error::Qmp(qmp::Error::NoSocket(_)) => ErrorKind::NoDomain,
+            Error::Qmp(qmp::Error::Io(_)) | Error::Qmp(qmp::Error::Closed) => ErrorKind::ConnectionLost,
+            Error::Qmp(_) => ErrorKind::Monitor,
+            Error::Reqwest(_) => ErrorKind::ConnectionLost,
+            Error::Remote(kind, ..) => kind,
+            Error::GroupSwitch { ref err, .. } | Error::Move { ref err, .. } => err.kind(),
+            _ => ErrorKind::Other
+        }
+    }
+    /// Devices a failed group switch or move couldn't roll back, left in the wrong state
+    pub fn stranded(&self) -> &[String] {
+        match *self {
+            Error::GroupSwitch { ref stranded, .. } | Error::Remote(_, _, ref stranded) => stranded,
+            Error::Move { ref err, .. } => err.stranded(),
+            _ => &[]
+        }
+    }
+    /// For a failed move, whether the devices went back to the domain they came from
+    pub fn restored(&self) -> Option<bool> {
+        match *self {
+            Error::Move { restored, .. } => Some(restored),
+            _ => None
+        }
+    }
+}
+
+pub trait Input: Send + Sync {
+    fn domains(&self) -> Arc<Domains>;
+    fn device(&self, domain: &str, evdev: &str) -> Result<Arc<Device>, Error>;
+    /// evdevs available for passthrough on the host running the domains
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, Error>;
+    fn group(&self, domain: &str, evdevs: &[String]) -> Result<Arc<Group>, Error> {
+        let devices = evdevs.iter()
+            .map(|e| self.device(domain, e))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Arc::new(DeviceGroup::new(domain, devices)))
+    }
+    /// Look up a device from a client request, backends that support per-request settings fall back to
+    /// `hotplug` if the request doesn't specify a method
+    fn request(&self, req: &DeviceRequest, _hotplug: Hotplug) -> Result<Arc<Device>, Error> {
+        self.device(&req.domain, &req.evdev)
+    }
+    fn request_group(&self, req: &GroupRequest, _hotplug: Hotplug) -> Result<Arc<Group>, Error> {
+        self.group(&req.domain, &req.evdevs)
+    }
+    /// Whether the backend can reach whatever runs the domains
+    fn health(&self) -> Health {
+        Health { connected: true, ..Default::default() }
+    }
+    /// Move `evdevs` from one domain to another, see `move_group()`
+    fn move_devices(&self, from: &str, to: &str, evdevs: &[String]) -> Result<(), Error> {
+        move_group(&*self.group(from, evdevs)?, &*self.group(to, evdevs)?)
+    }
+}
+
+/// How a backend's connection to whatever runs the domains is doing
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Health {
+    pub connected: bool,
+    /// Times the connection has been reestablished
+    pub reconnects: u32,
+    /// Failed attempts since the connection was lost
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+/// How a native device is hotplugged into a domain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hotplug {
+    /// `attach_device_flags()` / `detach_device_alias()` with a `Template`
+    Libvirt,
+    /// `device_add` / `device_del` straight to the QEMU monitor, bypassing the libvirt domain XML
+    Qmp,
+    /// `<input type='evdev'>`, QEMU grabs and releases the evdev without any PCI hotplug in the guest
+    Evdev,
+}
+impl Default for Hotplug {
+    fn default() -> Self {
+        Hotplug::Libvirt
+    }
+}
+
+/// Keys that make QEMU hand an `<input type='evdev'>` back and forth between host and guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GrabToggle {
+    CtrlCtrl,
+    AltAlt,
+    ShiftShift,
+    MetaMeta,
+    Scrolllock,
+    CtrlScrolllock,
+}
+impl GrabToggle {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            GrabToggle::CtrlCtrl => "ctrl-ctrl",
+            GrabToggle::AltAlt => "alt-alt",
+            GrabToggle::ShiftShift => "shift-shift",
+            GrabToggle::MetaMeta => "meta-meta",
+            GrabToggle::Scrolllock => "scrolllock",
+            GrabToggle::CtrlScrolllock => "ctrl-scrolllock",
+        }
+    }
+}
+impl Default for GrabToggle {
+    fn default() -> Self {
+        GrabToggle::CtrlCtrl
+    }
+}
+
+/// What a device specifier names
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    /// One or more evdevs, see `evdev::Specifier`
+    Evdev,
+    /// A whole USB device passed through as a `<hostdev>`, see `domain_xml::UsbSource`
+    Usb,
+}
+impl Default for DeviceKind {
+    fn default() -> Self {
+        DeviceKind::Evdev
+    }
+}
+/// Prefix for specifiers of USB devices, so they can be mixed with evdevs in one group
+pub const USB_PREFIX: &'static str = "usb:";
+impl DeviceKind {
+    /// The kind of `spec` and the rest of it, `kind` overrides the prefix
+    pub fn split(spec: &str, kind: Option<DeviceKind>) -> (DeviceKind, &str) {
+        let unprefixed = if spec.starts_with(USB_PREFIX) { &spec[USB_PREFIX.len()..] } else { spec };
+        match kind {
+            Some(k) => (k, if k == DeviceKind::Usb { unprefixed } else { spec }),
+            None if spec.starts_with(USB_PREFIX) => (DeviceKind::Usb, unprefixed),
+            None => (DeviceKind::Evdev, spec),
+        }
+    }
+}
+
+/// Settings for `Hotplug::Evdev`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grab {
+    #[serde(default)]
+    pub toggle: GrabToggle,
+}
+
+/// Which of a domain's definitions attaching and detaching change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Persistence {
+    /// Only the running domain, gone once it shuts down
+    Live,
+    /// Only the persistent definition, takes effect the next time the domain starts
+    Config,
+    Both,
+}
+impl Persistence {
+    pub fn live(&self) -> bool {
+        *self != Persistence::Config
+    }
+    pub fn config(&self) -> bool {
+        *self != Persistence::Live
     }
 }
+impl Default for Persistence {
+    fn default() -> Self {
+        Persistence::Live
+    }
+}
+
+/// Template and PCI address for one device specifier
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceTemplate {
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub address: Option<PciAddress>,
+}
+
+/// Picks the XML a native device is attached with, per device first, then per domain
+#[derive(Clone, Debug, Default)]
+pub struct Templates {
+    default: Template,
+    templates: HashMap<String, Template>,
+    domains: HashMap<String, String>,
+    devices: HashMap<String, DeviceTemplate>,
+}
+impl Templates {
+    pub fn new(templates: HashMap<String, Template>) -> Templates {
+        Templates {
+            templates,
+            ..Templates::default()
+        }
+    }
+    pub fn domain(mut self, domain: &str, template: &str) -> Result<Templates, Error> {
+        if !self.templates.contains_key(template) {
+            return Err(Error::NoTemplate(template.to_owned()));
+        }
+        self.domains.insert(domain.to_owned(), template.to_owned());
+        Ok(self)
+    }
+    pub fn device(mut self, spec: &str, device: DeviceTemplate) -> Result<Templates, Error> {
+        if let Some(ref t) = device.template {
+            if !self.templates.contains_key(t) {
+                return Err(Error::NoTemplate(t.clone()));
+            }
+        }
+        self.devices.insert(spec.to_owned(), device);
+        Ok(self)
+    }
+
+    pub fn select(&self, domain: &str, spec: &str) -> (&Template, Option<&PciAddress>) {
+        let device = self.devices.get(spec);
+        let name = device.and_then(|d| d.template.as_ref())
+            .or(self.domains.get(domain));
+        (name.and_then(|n| self.templates.get(n)).unwrap_or(&self.default), device.and_then(|d| d.address.as_ref()))
+    }
+}
+
+/// A set of devices as described by a client, before they've been looked up
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupRequest {
+    pub domain: String,
+    pub evdevs: Vec<String>,
+    pub target: Target,
+    /// Overrides the prefix of every specifier
+    #[serde(default)]
+    pub kind: Option<DeviceKind>,
+    #[serde(default)]
+    pub hotplug: Option<Hotplug>,
+    #[serde(default)]
+    pub persistence: Option<Persistence>,
+}
+
+/// A set of devices to move between domains as described by a client
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MoveRequest {
+    pub from: String,
+    pub to: String,
+    pub evdevs: Vec<String>,
+    /// Overrides the prefix of every specifier
+    #[serde(default)]
+    pub kind: Option<DeviceKind>,
+    #[serde(default)]
+    pub hotplug: Option<Hotplug>,
+    #[serde(default)]
+    pub persistence: Option<Persistence>,
+}
+impl MoveRequest {
+    fn group(&self, domain: &str, target: Target) -> GroupRequest {
+        GroupRequest {
+            domain: domain.to_owned(),
+            evdevs: self.evdevs.clone(),
+            target,
+            kind: self.kind,
+            hotplug: self.hotplug,
+            persistence: self.persistence,
+        }
+    }
+    pub fn source(&self) -> GroupRequest {
+        self.group(&self.from, Target::Detached)
+    }
+    pub fn destination(&self) -> GroupRequest {
+        self.group(&self.to, Target::Attached)
+    }
+}
+
+/// A device as described by a client, before it's been looked up
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceRequest {
+    pub domain: String,
+    pub evdev: String,
+    /// Taken from the specifier's prefix if missing
+    #[serde(default)]
+    pub kind: Option<DeviceKind>,
+    #[serde(default)]
+    pub hotplug: Option<Hotplug>,
+    #[serde(default)]
+    pub persistence: Option<Persistence>,
+}
+
+/// How devices are built on a `DomainOps`, shared by the backends that drive libvirt
+#[derive(Clone, Default)]
+pub struct NativeDevices {
+    policy: Arc<EvdevPolicy>,
+    templates: Arc<Templates>,
+    grab: Grab,
+    qmp_bus: QmpBus,
+    persistence: Persistence,
+    device_persistence: Arc<HashMap<String, Persistence>>,
+    device_hotplug: Arc<HashMap<String, Hotplug>>,
+}
+impl NativeDevices {
+    /// Restrict which evdevs can be passed through, defaults to anything under `/dev/input`
+    pub fn evdev_policy(mut self, policy: EvdevPolicy) -> NativeDevices {
+        self.policy = Arc::new(policy);
+        self
+    }
+    pub fn templates(mut self, templates: Templates) -> NativeDevices {
+        self.templates = Arc::new(templates);
+        self
+    }
+    pub fn grab(mut self, grab: Grab) -> NativeDevices {
+        self.grab = grab;
+        self
+    }
+    /// Used by `hotplug = "qmp"`
+    pub fn qmp_bus(mut self, bus: QmpBus) -> NativeDevices {
+        self.qmp_bus = bus;
+        self
+    }
+    /// Persistence for devices that requests don't specify it for, by specifier then `default`
+    pub fn persistence(mut self, default: Persistence, devices: HashMap<String, Persistence>) -> NativeDevices {
+        self.persistence = default;
+        self.device_persistence = Arc::new(devices);
+        self
+    }
+    /// Hotplug methods by specifier, taking priority over the domain's but not over a request's
+    pub fn device_hotplug(mut self, devices: HashMap<String, Hotplug>) -> NativeDevices {
+        self.device_hotplug = Arc::new(devices);
+        self
+    }
+    /// How `spec` is hotplugged if a request doesn't say, `default` being the domain's method
+    pub fn hotplug(&self, spec: &str, default: Hotplug) -> Hotplug {
+        self.device_hotplug.get(spec).cloned().unwrap_or(default)
+    }
+
+    /// Every evdev node of every physical device named by `specs`, and every USB device. `kind` and `hotplug`
+    /// override the kind and method of every specifier, `default` is the domain's method.
+    pub fn nodes(&self, domain: Arc<DomainOps>, specs: &[String], kind: Option<DeviceKind>, hotplug: Option<Hotplug>,
+                 default: Hotplug, persistence: Option<Persistence>) -> Result<Vec<Arc<Device>>, Error> {
+        let domain_name = domain.name()?;
+        let mut devices: Vec<Arc<Device>> = Vec::new();
+        for spec in specs {
+            let hotplug = hotplug.unwrap_or_else(|| self.hotplug(spec, default));
+            let persistence = persistence
+                .or(self.device_persistence.get(spec).cloned())
+                .unwrap_or(self.persistence);
+            if let (DeviceKind::Usb, usb) = DeviceKind::split(spec, kind) {
+                devices.push(Arc::new(NativeUsbDevice::new(domain.clone(), spec.to_owned(), usb.parse::<UsbSource>()?,
+                                                           &self.policy)?
+                                      .persistence(persistence)));
+                continue;
+            }
+
+            let nodes = evdev::resolve(spec)?;
+            let (template, address) = self.templates.select(&domain_name, spec);
+            if address.is_some() && nodes.len() > 1 {
+                return Err(Error::BadState("a PCI address can only be given to a device with a single evdev"));
+            }
 
-pub trait Input {
-    fn domains(&self) -> Box<Domains + '_>;
-    fn device<'a>(&'a self, domain: &'a str, evdev: &'a str) -> Result<Box<Device + '_>, Error>;
+            for node in nodes {
+                devices.push(self.native_device(domain.clone(), node, hotplug, persistence, template, address)?);
+            }
+        }
+        Ok(devices)
+    }
+    fn native_device(&self, domain: Arc<DomainOps>, evdev: String, hotplug: Hotplug, persistence: Persistence,
+                     template: &Template, address: Option<&PciAddress>) -> Result<Arc<Device>, Error> {
+        Ok(match hotplug {
+            Hotplug::Libvirt => Arc::new(NativeDevice::with_template(domain, evdev, &self.policy, template, address)?
+                                         .persistence(persistence)),
+            // QMP devices are never in the libvirt definitions
+            Hotplug::Qmp => Arc::new(NativeQmpDevice::new(domain, evdev, &self.policy, &self.qmp_bus)?),
+            Hotplug::Evdev => Arc::new(NativeEvdevDevice::new(domain, evdev, &self.policy, self.grab)?
+                                       .persistence(persistence)),
+        })
+    }
+    /// The physical device named by `spec` as a single `Device`, even if it has several evdev nodes
+    pub fn unit(&self, domain: Arc<DomainOps>, spec: &str, kind: Option<DeviceKind>, hotplug: Option<Hotplug>,
+                default: Hotplug, persistence: Option<Persistence>) -> Result<Arc<Device>, Error> {
+        let domain_name = domain.name()?;
+        let mut devices = self.nodes(domain, &[spec.to_owned()], kind, hotplug, default, persistence)?;
+        if devices.len() == 1 {
+            return Ok(devices.remove(0));
+        }
+        Ok(Arc::new(DeviceGroup::new(&domain_name, devices).name(spec)))
+    }
 }
 
-pub struct NativeInput(Connection);
+#[cfg(feature = "native")]
+#[derive(Clone)]
+pub struct NativeInput {
+    conn: Arc<Supervisor>,
+    hotplug: Hotplug,
+    devices: NativeDevices,
+}
+#[cfg(feature = "native")]
 impl NativeInput {
-    pub fn new<'a>(conn: Connection) -> Box<Input + 'a> {
-        Box::new(NativeInput(conn))
+    pub fn new(conn: Arc<Supervisor>) -> Arc<Input> {
+        NativeInput::with_hotplug(conn, Hotplug::default())
+    }
+    pub fn with_hotplug(conn: Arc<Supervisor>, hotplug: Hotplug) -> Arc<Input> {
+        Arc::new(NativeInput::from_conn(conn, hotplug))
+    }
+    pub fn from_conn(conn: Arc<Supervisor>, hotplug: Hotplug) -> NativeInput {
+        NativeInput {
+            conn,
+            hotplug,
+            devices: NativeDevices::default(),
+        }
+    }
+    /// See `NativeDevices::evdev_policy()`
+    pub fn evdev_policy(mut self, policy: EvdevPolicy) -> NativeInput {
+        self.devices = self.devices.evdev_policy(policy);
+        self
+    }
+    pub fn templates(mut self, templates: Templates) -> NativeInput {
+        self.devices = self.devices.templates(templates);
+        self
+    }
+    pub fn grab(mut self, grab: Grab) -> NativeInput {
+        self.devices = self.devices.grab(grab);
+        self
+    }
+    pub fn qmp_bus(mut self, bus: QmpBus) -> NativeInput {
+        self.devices = self.devices.qmp_bus(bus);
+        self
+    }
+    /// See `NativeDevices::persistence()`
+    pub fn persistence(mut self, default: Persistence, devices: HashMap<String, Persistence>) -> NativeInput {
+        self.devices = self.devices.persistence(default, devices);
+        self
+    }
+    /// See `NativeDevices::device_hotplug()`
+    pub fn device_hotplug(mut self, devices: HashMap<String, Hotplug>) -> NativeInput {
+        self.devices = self.devices.device_hotplug(devices);
+        self
+    }
+    /// See `NativeDevices::hotplug()`
+    pub fn hotplug(&self, spec: &str, default: Hotplug) -> Hotplug {
+        self.devices.hotplug(spec, default)
+    }
+
+    /// The current libvirt connection
+    pub fn conn(&self) -> Arc<Connection> {
+        self.conn.get()
+    }
+    pub fn supervisor(&self) -> &Arc<Supervisor> {
+        &self.conn
+    }
+    /// Look up a device from a client request, falling back to the device's configured method and then `hotplug` if
+    /// the request doesn't specify one
+    pub fn request(&self, req: &DeviceRequest, hotplug: Hotplug) -> Result<Arc<Device>, Error> {
+        let dom = self.lookup(&req.domain)?;
+        self.devices.unit(dom, &req.evdev, req.kind, req.hotplug, hotplug, req.persistence)
+    }
+    pub fn request_group(&self, req: &GroupRequest, hotplug: Hotplug) -> Result<DeviceGroup, Error> {
+        let dom = self.lookup(&req.domain)?;
+        let devices = self.devices.nodes(dom, &req.evdevs, req.kind, req.hotplug, hotplug, req.persistence)?;
+        Ok(DeviceGroup::new(&req.domain, devices))
+    }
+
+    fn lookup(&self, domain: &str) -> Result<Arc<DomainOps>, Error> {
+        Ok(Arc::new(Domain::from(::virt::domain::Domain::lookup_by_name(&self.conn.get(), domain)?)))
     }
 }
+#[cfg(feature = "native")]
 impl Input for NativeInput {
-    fn domains(&self) -> Box<Domains + '_> {
-        Box::new(NativeDomains::new(&self.0))
+    fn request(&self, req: &DeviceRequest, hotplug: Hotplug) -> Result<Arc<Device>, Error> {
+        NativeInput::request(self, req, hotplug)
+    }
+    fn request_group(&self, req: &GroupRequest, hotplug: Hotplug) -> Result<Arc<Group>, Error> {
+        Ok(Arc::new(NativeInput::request_group(self, req, hotplug)?))
+    }
+    fn health(&self) -> Health {
+        self.conn.health()
+    }
+    fn domains(&self) -> Arc<Domains> {
+        Arc::new(NativeDomains::new(self.conn.clone()))
+    }
+    fn device(&self, domain: &str, evdev: &str) -> Result<Arc<Device>, Error> {
+        let dom = self.lookup(domain)?;
+        self.devices.unit(dom, evdev, None, None, self.hotplug, None)
+    }
+    fn group(&self, domain: &str, evdevs: &[String]) -> Result<Arc<Group>, Error> {
+        let dom = self.lookup(domain)?;
+        Ok(Arc::new(DeviceGroup::new(domain, self.devices.nodes(dom, evdevs, None, None, self.hotplug, None)?)))
     }
-    fn device(&self, domain: &str, evdev: &str) -> Result<Box<Device + '_>, Error> {
-        let dom = Domain::from(::virt::domain::Domain::lookup_by_name(&self.0, domain)?);
-        Ok(Box::new(NativeDevice::new(dom, evdev.to_string())?))
+    #[cfg(target_os = "linux")]
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, Error> {
+        Ok(evdev::list()?)
     }
+    #[cfg(not(target_os = "linux"))]
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, Error> {
+        Err(Error::Unsupported("evdev discovery"))
+    }
+}
+
+#[cfg(feature = "http")]
+#[derive(Deserialize)]
+struct HttpErrorMsg {
+    code: ErrorKind,
+    message: String,
+    #[serde(default)]
+    stranded: Vec<String>,
 }
+/// Turn a failed response from the server back into a typed `Error`
+#[cfg(feature = "http")]
+fn http_error(res: &mut reqwest::Response) -> Error {
+    let text = match res.text() {
+        Ok(t) => t,
+        Err(_) => return Error::Reqwest(format!("request failed with status {}", res.status()))
+    };
 
-#[cfg(target_os = "windows")]
+    match ::serde_json::from_str::<HttpErrorMsg>(&text) {
+        Ok(msg) => Error::Remote(msg.code, msg.message, msg.stranded),
+        Err(_) => Error::HttpDecode(text)
+    }
+}
+
+#[cfg(feature = "http")]
+#[derive(Clone)]
 pub struct HttpInput {
     client: reqwest::Client,
     host: String,
 }
-#[cfg(target_os = "windows")]
+#[cfg(feature = "http")]
 impl HttpInput {
-    pub fn new<'a>(client: reqwest::Client, host: &str) -> Box<Input + 'a> {
-        Box::new(HttpInput {
+    pub fn new(client: reqwest::Client, host: &str) -> Arc<Input> {
+        Arc::new(HttpInput {
             client,
             host: host.to_owned(),
         })
     }
 }
-#[cfg(target_os = "windows")]
+#[cfg(feature = "http")]
 impl Input for HttpInput {
-    fn domains(&self) -> Box<Domains + '_> {
-        Box::new(HttpDomains::new(&self.client, &self.host))
+    fn domains(&self) -> Arc<Domains> {
+        Arc::new(HttpDomains::new(self.client.clone(), &self.host))
+    }
+    fn device(&self, domain: &str, evdev: &str) -> Result<Arc<Device>, Error> {
+        Ok(Arc::new(HttpDevice::new(self.client.clone(), &self.host, domain, evdev)))
+    }
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, Error> {
+        let mut res = self.client
+            .get(&format!("{}/evdevs", self.host))
+            .send()
+            .map_err(|e| Error::Reqwest(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(http_error(&mut res));
+        }
+
+        res.json().map_err(|e| Error::HttpDecode(e.to_string()))
     }
-    fn device<'a>(&'a self, domain: &'a str, evdev: &'a str) -> Result<Box<Device + '_>, Error> {
-        Ok(Box::new(HttpDevice::new(&self.client, &self.host, domain, evdev)))
+    fn group(&self, domain: &str, evdevs: &[String]) -> Result<Arc<Group>, Error> {
+        Ok(Arc::new(HttpGroup::new(self.client.clone(), &self.host, domain, evdevs)))
+    }
+    fn move_devices(&self, from: &str, to: &str, evdevs: &[String]) -> Result<(), Error> {
+        let req = MoveRequest {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            evdevs: evdevs.to_vec(),
+            kind: None,
+            hotplug: None,
+            persistence: None,
+        };
+
+        let mut res = self.client
+            .post(&format!("{}/move", self.host))
+            .json(&req)
+            .send()
+            .map_err(|e| Error::Reqwest(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(http_error(&mut res));
+        }
+        Ok(())
     }
 }
 
-pub trait Domains {
+pub trait Domains: Send + Sync {
     fn list(&self) -> Result<Vec<String>, Error>;
 }
 
-pub struct NativeDomains<'a>(&'a Connection);
-impl<'a> NativeDomains<'a> {
-    pub fn new(conn: &'a Connection) -> NativeDomains<'a> {
+#[cfg(feature = "native")]
+#[derive(Clone)]
+pub struct NativeDomains(Arc<Supervisor>);
+#[cfg(feature = "native")]
+impl NativeDomains {
+    pub fn new(conn: Arc<Supervisor>) -> NativeDomains {
         NativeDomains(conn)
     }
 }
-impl<'a> Domains for NativeDomains<'a> {
+#[cfg(feature = "native")]
+impl Domains for NativeDomains {
     fn list(&self) -> Result<Vec<String>, Error> {
-        self.0.list_all_domains(::virt::connect::VIR_CONNECT_LIST_DOMAINS_ACTIVE)?
+        self.0.get().list_all_domains(::virt::connect::VIR_CONNECT_LIST_DOMAINS_ACTIVE)?
             .iter()
             .map(|d| d.get_name())
             .collect::<Result<Vec<String>, ::virt::error::Error>>()
@@ -101,246 +770,872 @@ impl<'a> Domains for NativeDomains<'a> {
     }
 }
 
-#[cfg(target_os = "windows")]
-pub struct HttpDomains<'a> {
-    client: &'a reqwest::Client,
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub struct HttpDomains {
+    client: reqwest::Client,
     url: String
 }
-#[cfg(target_os = "windows")]
-impl<'a> HttpDomains<'a> {
-    pub fn new(client: &'a reqwest::Client, host: &'a str) -> HttpDomains<'a> {
+#[cfg(feature = "http")]
+impl HttpDomains {
+    pub fn new(client: reqwest::Client, host: &str) -> HttpDomains {
         HttpDomains {
             client,
             url: format!("{}/domains", host)
         }
     }
 }
-#[cfg(target_os = "windows")]
-impl<'a> Domains for HttpDomains<'a> {
+#[cfg(feature = "http")]
+impl Domains for HttpDomains {
     fn list(&self) -> Result<Vec<String>, Error> {
-        self.client
+        let mut res = self.client
             .get(&self.url)
             .send()
-            .map_err(|e| Error::Reqwest(e.to_string()))?
-            .json()
-            .map_err(|e| Error::Reqwest(e.to_string()))
+            .map_err(|e| Error::Reqwest(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(http_error(&mut res));
+        }
+
+        res.json().map_err(|e| Error::HttpDecode(e.to_string()))
     }
 }
 
-pub trait Device {
+#[derive(Debug)]
+pub enum Status {
+    Attached,
+    Detached,
+    /// The device's state couldn't be determined (e.g. libvirt or the server was unreachable)
+    Unknown(Error),
+}
+impl Status {
+    pub fn is_attached(&self) -> bool {
+        match *self {
+            Status::Attached => true,
+            _ => false
+        }
+    }
+    pub fn is_unknown(&self) -> bool {
+        match *self {
+            Status::Unknown(_) => true,
+            _ => false
+        }
+    }
+}
+impl From<bool> for Status {
+    fn from(attached: bool) -> Self {
+        if attached {
+            Status::Attached
+        } else {
+            Status::Detached
+        }
+    }
+}
+
+pub trait Device: Send + Sync {
     fn evdev(&self) -> &str;
     fn domain(&self) -> &str;
 
-    fn attached(&self) -> bool;
+    /// Whether the running domain has the device, or the persistent definition for devices that only change
+    /// that one (`Persistence::Config`)
+    fn status(&self) -> Status;
+    /// Whether the domain's persistent definition has the device
+    fn persistent_status(&self) -> Status {
+        Status::Unknown(Error::Unsupported("persistent status"))
+    }
 
     fn attach(&self) -> Result<(), Error>;
     fn detach(&self) -> Result<(), Error>;
     fn toggle(&self) -> Result<bool, Error> {
-        Ok(if self.attached() {
-            self.detach()?;
-            true
-        } else {
-            self.attach()?;
-            false
-        })
+        match self.status() {
+            Status::Attached => {
+                self.detach()?;
+                Ok(true)
+            },
+            Status::Detached => {
+                self.attach()?;
+                Ok(false)
+            },
+            Status::Unknown(e) => Err(e)
+        }
     }
 }
 
-static mut GLOBAL_CONN: Option<Connection> = None;
-pub unsafe fn open_native_global_conn(uri: &str) -> Result<(), ::virt::error::Error> {
-    GLOBAL_CONN = Some(Connection::open(uri)?);
+/// What to switch every device in a `Group` to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Attached,
+    Detached,
+    /// Detach everything if any device is attached, attach everything otherwise
+    Toggle,
+}
+
+/// A set of devices on one domain that are always switched together
+pub trait Group: Send + Sync {
+    fn domain(&self) -> &str;
+
+    /// Switch every device to `target`, rolling back devices that were already switched if any of them
+    /// fails. Returns whether the devices ended up attached.
+    fn switch(&self, target: Target) -> Result<bool, Error>;
+
+    /// Whether each device is attached, to put them back with `restore()`
+    fn states(&self) -> Result<Vec<bool>, Error> {
+        Err(Error::Unsupported("per-device group states"))
+    }
+    /// Switch each device to what `states()` returned for it
+    fn restore(&self, _states: &[bool]) -> Result<(), Error> {
+        Err(Error::Unsupported("per-device group states"))
+    }
+}
+
+/// Detach every device in `from` and attach them to `to`, so they're never attached to both. If `to` fails
+/// whatever it got is detached again and `from` gets back exactly the devices it had.
+pub fn move_group(from: &Group, to: &Group) -> Result<(), Error> {
+    if from.domain() == to.domain() {
+        return Err(Error::BadState("can't move devices to the domain they're already on"));
+    }
+
+    debug!("moving devices from '{}' to '{}'", from.domain(), to.domain());
+    // groups that can't say fall back to giving `from` everything
+    let before = from.states().ok();
+    from.switch(Target::Detached)?;
+    if let Err(e) = to.switch(Target::Attached) {
+        error!("failed to attach devices to '{}', giving them back to '{}': {}", to.domain(), from.domain(), e);
+        // anything `to` couldn't roll back would end up attached to both
+        let restored = match to.switch(Target::Detached) {
+            Err(e) => {
+                error!("failed to detach leftover devices from '{}', leaving '{}' without them: {}", to.domain(),
+                       from.domain(), e);
+                false
+            },
+            Ok(_) => {
+                let res = match before {
+                    Some(ref states) => from.restore(states),
+                    None => from.switch(Target::Attached).map(|_| ())
+                };
+                if let Err(ref e) = res {
+                    error!("failed to give devices back to '{}': {}", from.domain(), e);
+                }
+                res.is_ok()
+            }
+        };
+
+        return Err(Error::Move {
+            to: to.domain().to_owned(),
+            err: Box::new(e),
+            restored,
+        });
+    }
     Ok(())
 }
-pub unsafe fn close_native_global_conn() -> Result<(), Error> {
-    match GLOBAL_CONN {
-        None => Err(Error::GlobalConnNotOpen),
-        Some(ref mut conn) => conn.close().map(|_| ()).map_err(|e| Error::Virt(e))
+
+/// How long to wait between switching devices in a group, any shorter and keys end up stuck down
+pub const DEFAULT_SETTLE: Duration = Duration::from_millis(300);
+
+/// Devices that are switched together, can also stand in for a single `Device` (e.g. all the evdev nodes of
+/// one physical keyboard)
+#[derive(Clone)]
+pub struct DeviceGroup {
+    name: String,
+    domain: String,
+    devices: Vec<Arc<Device>>,
+    settle: Duration,
+}
+impl DeviceGroup {
+    pub fn new(domain: &str, devices: Vec<Arc<Device>>) -> DeviceGroup {
+        DeviceGroup {
+            name: devices.iter().map(|d| d.evdev()).collect::<Vec<_>>().join(","),
+            domain: domain.to_owned(),
+            devices,
+            settle: DEFAULT_SETTLE,
+        }
+    }
+    /// What `Device::evdev()` returns for the group
+    pub fn name(mut self, name: &str) -> DeviceGroup {
+        self.name = name.to_owned();
+        self
+    }
+    pub fn settle(mut self, settle: Duration) -> DeviceGroup {
+        self.settle = settle;
+        self
+    }
+
+    pub fn devices(&self) -> &[Arc<Device>] {
+        &self.devices
     }
 }
-pub fn get_native_global_conn() -> Option<&'static Connection> {
-    unsafe {
-        match GLOBAL_CONN {
-            Some(ref conn) => Some(&conn),
-            None => None
+impl Group for DeviceGroup {
+    fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn switch(&self, target: Target) -> Result<bool, Error> {
+        let states = self.states()?;
+        let attach = match target {
+            Target::Attached => true,
+            Target::Detached => false,
+            Target::Toggle => !states.iter().any(|a| *a),
+        };
+        debug!("switching {} devices on '{}' to attached: {}", self.devices.len(), self.domain, attach);
+
+        let mut switched: Vec<&Arc<Device>> = Vec::with_capacity(self.devices.len());
+        for (device, _) in self.devices.iter().zip(states).filter(|&(_, a)| a != attach) {
+            if !switched.is_empty() {
+                thread::sleep(self.settle);
+            }
+
+            let res = if attach {
+                device.attach()
+            } else {
+                device.detach()
+            };
+            if let Err(e) = res {
+                error!("failed to switch device at '{}', rolling back {} devices: {}", device.evdev(), switched.len(), e);
+
+                let mut stranded = Vec::new();
+                for d in switched.iter().rev() {
+                    thread::sleep(self.settle);
+                    let undo = if attach {
+                        d.detach()
+                    } else {
+                        d.attach()
+                    };
+                    if let Err(e) = undo {
+                        error!("failed to roll back device at '{}': {}", d.evdev(), e);
+                        stranded.push(d.evdev().to_owned());
+                    }
+                }
+
+                return Err(Error::GroupSwitch {
+                    evdev: device.evdev().to_owned(),
+                    err: Box::new(e),
+                    stranded,
+                });
+            }
+            switched.push(device);
+        }
+
+        Ok(attach)
+    }
+
+    fn states(&self) -> Result<Vec<bool>, Error> {
+        let mut states = Vec::with_capacity(self.devices.len());
+        for status in parallel(&self.devices, |d| Ok(d.status())) {
+            match status? {
+                Status::Attached => states.push(true),
+                Status::Detached => states.push(false),
+                Status::Unknown(e) => return Err(e)
+            }
         }
+        Ok(states)
+    }
+    fn restore(&self, states: &[bool]) -> Result<(), Error> {
+        let current = self.states()?;
+        let mut switched = false;
+        for ((device, &attach), attached) in self.devices.iter().zip(states).zip(current) {
+            if attach == attached {
+                continue;
+            }
+            if switched {
+                thread::sleep(self.settle);
+            }
+
+            if attach {
+                device.attach()?;
+            } else {
+                device.detach()?;
+            }
+            switched = true;
+        }
+        Ok(())
     }
 }
 
-#[derive(Serialize)]
-pub struct NativeDevice {
-    evdev: String,
-    domain: Domain,
+impl Device for DeviceGroup {
+    fn evdev(&self) -> &str {
+        &self.name
+    }
+    fn domain(&self) -> &str {
+        &self.domain
+    }
 
-    #[serde(skip)]
-    domain_name: String,
-    #[serde(skip)]
+    /// Attached if any device in the group is, same as `Target::Toggle`
+    fn status(&self) -> Status {
+        match self.states() {
+            Ok(states) => states.iter().any(|a| *a).into(),
+            Err(e) => Status::Unknown(e)
+        }
+    }
+    fn persistent_status(&self) -> Status {
+        let mut attached = false;
+        for status in parallel(&self.devices, |d| Ok(d.persistent_status())) {
+            match status {
+                Ok(Status::Attached) => attached = true,
+                Ok(Status::Detached) => {},
+                Ok(Status::Unknown(e)) | Err(e) => return Status::Unknown(e)
+            }
+        }
+        attached.into()
+    }
+    fn attach(&self) -> Result<(), Error> {
+        if self.states()?.iter().all(|a| *a) {
+            return Err(Error::AlreadyAttached(self.name.clone()));
+        }
+        self.switch(Target::Attached).map(|_| ())
+    }
+    fn detach(&self) -> Result<(), Error> {
+        if !self.states()?.iter().any(|a| *a) {
+            return Err(Error::NotAttached(self.name.clone()));
+        }
+        self.switch(Target::Detached).map(|_| ())
+    }
+}
+
+lazy_static! {
+    /// Shared by every `parallel` call, so status checks don't spawn threads of their own
+    static ref POOL: CpuPool = CpuPoolBuilder::new()
+        .after_start(|| IN_POOL.with(|p| p.set(true)))
+        .create();
+}
+thread_local! {
+    /// Whether this is a `POOL` thread, which mustn't wait on tasks queued behind it
+    static IN_POOL: Cell<bool> = Cell::new(false);
+}
+
+/// Run `op` on every device at the same time on a shared pool, returning results in the same order. Nested
+/// groups (already on the pool) run their devices one after the other instead, waiting on the pool from one of
+/// its own threads would deadlock once every thread is waiting.
+pub fn parallel<T, F>(devices: &[Arc<Device>], op: F) -> Vec<Result<T, Error>>
+where
+    T: Send + 'static,
+    F: Fn(&Device) -> Result<T, Error> + Send + Sync + 'static,
+{
+    // Not worth a trip through the pool
+    if devices.len() == 1 || IN_POOL.with(|p| p.get()) {
+        return devices.iter().map(|d| op(&**d)).collect();
+    }
+
+    let op = Arc::new(op);
+    let futures: Vec<_> = devices.iter()
+        .map(|d| {
+            let (device, op) = (d.clone(), op.clone());
+            POOL.spawn_fn(move || Ok::<_, ()>(op(&*device)))
+        })
+        .collect();
+
+    futures.into_iter()
+        .map(|f| f.wait().unwrap_or(Err(Error::BadState("device operation panicked"))))
+        .collect()
+}
+
+/// The calls devices make on their domain, so they work over any kind of connection to libvirt
+pub trait DomainOps: Send + Sync {
+    fn name(&self) -> Result<String, Error>;
+    fn xml(&self, flags: u32) -> Result<String, Error>;
+    fn attach_device(&self, xml: &str, flags: u32) -> Result<(), Error>;
+    fn detach_device_alias(&self, alias: &str, flags: u32) -> Result<(), Error>;
+    /// Run a QMP command, returning what it returns
+    fn qmp(&self, command: &str) -> Result<::serde_json::Value, Error>;
+}
+#[cfg(feature = "native")]
+impl DomainOps for Domain {
+    fn name(&self) -> Result<String, Error> {
+        Ok(self.get_name()?)
+    }
+    fn xml(&self, flags: u32) -> Result<String, Error> {
+        Ok(self.get_xml_desc(flags)?)
+    }
+    fn attach_device(&self, xml: &str, flags: u32) -> Result<(), Error> {
+        self.attach_device_flags(xml, flags)?;
+        Ok(())
+    }
+    fn detach_device_alias(&self, alias: &str, flags: u32) -> Result<(), Error> {
+        Ok(Domain::detach_device_alias(self, alias, flags)?)
+    }
+    fn qmp(&self, command: &str) -> Result<::serde_json::Value, Error> {
+        Ok(Domain::qmp(self, command)?)
+    }
+}
+
+/// Each of the definitions of `domain` that `persistence` covers, with the flag that affects it
+fn definitions(domain: &DomainOps, persistence: Persistence) -> Result<Vec<(u32, DomainXml)>, Error> {
+    let mut defs = Vec::with_capacity(2);
+    if persistence.live() {
+        defs.push((VIR_DOMAIN_AFFECT_LIVE, DomainXml::parse(&domain.xml(VIR_DOMAIN_NONE)?)?));
+    }
+    if persistence.config() {
+        defs.push((VIR_DOMAIN_AFFECT_CONFIG, DomainXml::parse(&domain.xml(VIR_DOMAIN_XML_INACTIVE)?)?));
+    }
+    Ok(defs)
+}
+
+/// What a `ManagedInput` is, to spot copies of it that the admin added by hand
+#[derive(Clone)]
+enum ManagedKind {
+    /// With the node the evdev resolved to, which is what the domain XML has
+    Input(InputKind, String),
+    Usb(UsbSource),
+}
+
+/// A device we attach to (some of) a domain's definitions under a user alias
+#[derive(Clone)]
+struct ManagedInput {
+    kind: ManagedKind,
+    evdev: String,
+    domain: Arc<DomainOps>,
+    alias: String,
     xml: String,
+    persistence: Persistence,
 }
+impl ManagedInput {
+    fn new(kind: ManagedKind, evdev: &str, domain: Arc<DomainOps>, xml: String) -> ManagedInput {
+        ManagedInput {
+            kind,
+            evdev: evdev.to_owned(),
+            domain,
+            alias: domain_xml::alias(evdev),
+            xml,
+            persistence: Persistence::default(),
+        }
+    }
 
-impl NativeDevice {
-    pub fn new(domain: Domain, evdev: String) -> Result<Self, Error> {
-        // we only want to check if the evdev exists on the host
-        #[cfg(target_os = "linux")]
-        {
-            let evdev_path = PathBuf::from(evdev.clone());
-            if !evdev_path.exists() {
-                return Err(Error::BadEvdev(evdev));
+    fn managed(&self, xml: &DomainXml) -> bool {
+        match self.kind {
+            ManagedKind::Input(..) => xml.managed(&self.alias).is_some(),
+            ManagedKind::Usb(_) => xml.managed_hostdev(&self.alias).is_some(),
+        }
+    }
+    /// Whether `xml` has the device under any alias
+    fn present(&self, xml: &DomainXml) -> bool {
+        match self.kind {
+            ManagedKind::Input(kind, ref path) => xml.input(kind, path).or_else(|| xml.input(kind, &self.evdev)).is_some(),
+            ManagedKind::Usb(ref source) => xml.usb(source).is_some(),
+        }
+    }
+
+    /// Attached if the domain has the device at all, even if the admin added it
+    fn status(&self, flags: u32) -> Status {
+        match self.domain.xml(flags).and_then(|x| Ok(DomainXml::parse(&x)?)) {
+            Ok(xml) => (self.managed(&xml) || self.present(&xml)).into(),
+            Err(e) => Status::Unknown(e)
+        }
+    }
+
+    /// Status in the definition switching changes, the persistent one with `Persistence::Config` so a device
+    /// that's only ever attached there can still be switched off again
+    fn current(&self) -> Status {
+        match self.persistence.live() {
+            true => self.status(VIR_DOMAIN_NONE),
+            false => self.status(VIR_DOMAIN_XML_INACTIVE)
+        }
+    }
+
+    /// Add the input to every covered definition that doesn't have it yet
+    fn attach(&self) -> Result<(), Error> {
+        let mut flags = 0;
+        for (flag, xml) in definitions(&*self.domain, self.persistence)? {
+            if self.managed(&xml) {
+                continue;
+            }
+            if self.present(&xml) {
+                return Err(Error::Unmanaged(self.evdev.clone()));
             }
+            flags |= flag;
+        }
+        if flags == 0 {
+            return Err(Error::AlreadyAttached(self.evdev.clone()));
+        }
 
-            let flags = SFlag::from_bits_truncate(match stat(&evdev_path) {
-                Ok(s) => s,
-                Err(_) => return Err(Error::BadEvdev(evdev))
-            }.st_mode);
-            debug!("evdev {:?} st_mode: {:#x}", evdev, flags);
-            if !flags.contains(SFlag::S_IFCHR) {
-                return Err(Error::BadEvdev(evdev));
+        self.domain.attach_device(&self.xml, flags)?;
+        Ok(())
+    }
+    /// Remove the input from every covered definition that has it
+    fn detach(&self) -> Result<(), Error> {
+        let (mut flags, mut unmanaged) = (0, false);
+        for (flag, xml) in definitions(&*self.domain, self.persistence)? {
+            if self.managed(&xml) {
+                flags |= flag;
+            } else if self.present(&xml) {
+                unmanaged = true;
             }
         }
+        if flags == 0 {
+            return Err(match unmanaged {
+                true => Error::Unmanaged(self.evdev.clone()),
+                false => Error::NotAttached(self.evdev.clone())
+            });
+        }
 
-        let domain_name = domain.get_name()?;
-        let xml = format!(include_str!("attach_detach.xml"), evdev=evdev);
+        self.domain.detach_device_alias(&self.alias, flags)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct NativeDevice {
+    domain_name: String,
+    input: ManagedInput,
+}
+
+impl NativeDevice {
+    pub fn new(domain: Arc<DomainOps>, evdev: String, policy: &EvdevPolicy) -> Result<Self, Error> {
+        NativeDevice::with_template(domain, evdev, policy, &Template::default(), None)
+    }
+    pub fn with_template(domain: Arc<DomainOps>, evdev: String, policy: &EvdevPolicy,
+                         template: &Template, address: Option<&PciAddress>) -> Result<Self, Error> {
+        let path = policy.check(&evdev)?.to_string_lossy().into_owned();
+        let domain_name = domain.name()?;
+        let xml = template.render(&path, &domain_xml::alias(&evdev), address);
         Ok(NativeDevice {
-            evdev,
-            domain,
             domain_name,
-            xml
+            input: ManagedInput::new(ManagedKind::Input(InputKind::Passthrough, path), &evdev, domain, xml),
         })
     }
+    pub fn persistence(mut self, persistence: Persistence) -> NativeDevice {
+        self.input.persistence = persistence;
+        self
+    }
 }
 
 impl Device for NativeDevice {
     fn evdev(&self) -> &str {
-        &self.evdev
+        &self.input.evdev
     }
     fn domain(&self) -> &str {
         &self.domain_name
     }
 
-    fn attached(&self) -> bool {
-        match self.domain.get_xml_desc(VIR_DOMAIN_NONE) {
-            Ok(xml) => xml.contains(&self.evdev),
-            Err(_) => false
-        }
+    fn status(&self) -> Status {
+        self.input.current()
+    }
+    fn persistent_status(&self) -> Status {
+        self.input.status(VIR_DOMAIN_XML_INACTIVE)
     }
 
     fn attach(&self) -> Result<(), Error> {
-        if self.attached() {
-            return Err(Error::BadState("Device already attached!"));
-        }
+        self.input.attach()
+    }
+    fn detach(&self) -> Result<(), Error> {
+        self.input.detach()
+    }
+}
 
-        match self.domain.attach_device_flags(&self.xml, VIR_DOMAIN_AFFECT_LIVE) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(
-                if e.code == libvirt::VIR_ERR_INTERNAL_ERROR &&
-                e.message == format!("internal error: unable to execute QEMU command \'device_add\': {}: failed to get exclusive access: Device or resource busy", self.evdev) {
-                Error::BadState("Device already attached!")
-            } else {
-                e.into()
-            })
+/// An `<input type='evdev'>`, attached means the domain has the input. QEMU doesn't let anything but the toggle
+/// keys move input-linux's grab, so while the input is set up they're the only way to switch between host and
+/// guest; attaching and detaching add and remove the whole input.
+#[derive(Clone)]
+pub struct NativeEvdevDevice {
+    domain_name: String,
+    input: ManagedInput,
+}
+
+impl NativeEvdevDevice {
+    pub fn new(domain: Arc<DomainOps>, evdev: String, policy: &EvdevPolicy, grab: Grab) -> Result<Self, Error> {
+        let path = policy.check(&evdev)?.to_string_lossy().into_owned();
+        NativeEvdevDevice::checked(domain, evdev, path, grab)
+    }
+    /// With `path`, what `evdev` resolves to, already checked against the policy
+    pub(crate) fn checked(domain: Arc<DomainOps>, evdev: String, path: String, grab: Grab) -> Result<Self, Error> {
+        let domain_name = domain.name()?;
+        let xml = format!(include_str!("grab.xml"), evdev=domain_xml::escape(&path),
+                          alias=domain_xml::alias(&evdev), toggle=grab.toggle.as_str());
+        Ok(NativeEvdevDevice {
+            domain_name,
+            input: ManagedInput::new(ManagedKind::Input(InputKind::Evdev, path), &evdev, domain, xml),
+        })
+    }
+    /// `Persistence::Config` sets the grab up once, the guest has the evdev from every boot after
+    pub fn persistence(mut self, persistence: Persistence) -> NativeEvdevDevice {
+        self.input.persistence = persistence;
+        self
+    }
+}
+
+impl Device for NativeEvdevDevice {
+    fn evdev(&self) -> &str {
+        &self.input.evdev
+    }
+    fn domain(&self) -> &str {
+        &self.domain_name
+    }
+
+    /// Detached if the running domain doesn't have the input. Once it does, which side has the evdev can't be
+    /// told: QEMU doesn't expose input-linux's grab state, and only the toggle keys switch it.
+    fn status(&self) -> Status {
+        match self.input.current() {
+            Status::Attached if self.input.persistence.live() =>
+                Status::Unknown(Error::Unsupported("telling which side has a grabbed evdev")),
+            s => s
         }
     }
+    /// Whether the input is in the persistent definition, QEMU grabs it as soon as the domain starts
+    fn persistent_status(&self) -> Status {
+        self.input.status(VIR_DOMAIN_XML_INACTIVE)
+    }
+
+    /// Set the input up, QEMU grabs the evdev for the guest as it creates it. No PCI hotplug is involved, the
+    /// events go to the guest's existing keyboard and mouse.
+    fn attach(&self) -> Result<(), Error> {
+        self.input.attach()
+    }
+    /// Take the input away, handing the evdev back to the host whichever side had it
+    fn detach(&self) -> Result<(), Error> {
+        self.input.detach()
+    }
+}
+
+/// A whole USB device as a `<hostdev>`, `evdev()` is its specifier
+#[derive(Clone)]
+pub struct NativeUsbDevice {
+    domain_name: String,
+    input: ManagedInput,
+}
+
+impl NativeUsbDevice {
+    pub fn new(domain: Arc<DomainOps>, spec: String, source: UsbSource, policy: &EvdevPolicy) -> Result<Self, Error> {
+        policy.check_usb(&source)?;
+        let domain_name = domain.name()?;
+        let xml = source.to_xml(&domain_xml::alias(&spec));
+        Ok(NativeUsbDevice {
+            domain_name,
+            input: ManagedInput::new(ManagedKind::Usb(source), &spec, domain, xml),
+        })
+    }
+    pub fn persistence(mut self, persistence: Persistence) -> NativeUsbDevice {
+        self.input.persistence = persistence;
+        self
+    }
+}
+
+impl Device for NativeUsbDevice {
+    fn evdev(&self) -> &str {
+        &self.input.evdev
+    }
+    fn domain(&self) -> &str {
+        &self.domain_name
+    }
+
+    fn status(&self) -> Status {
+        self.input.current()
+    }
+    fn persistent_status(&self) -> Status {
+        self.input.status(VIR_DOMAIN_XML_INACTIVE)
+    }
+
+    fn attach(&self) -> Result<(), Error> {
+        self.input.attach()
+    }
     fn detach(&self) -> Result<(), Error> {
-        if !self.attached() {
-            return Err(Error::BadState("Device not attached!"));
+        self.input.detach()
+    }
+}
+
+/// Where devices hotplugged over QMP are put. `pci.0` doesn't exist on q35 machines and `pcie.0` can't
+/// hotplug, so those need a `pcie-root-port` or `pci-bridge` (e.g. `pci.1`).
+#[derive(Clone, Debug, Deserialize)]
+pub struct QmpBus {
+    /// QEMU id of the bus
+    pub id: String,
+    #[serde(default = "QmpBus::default_first_slot")]
+    pub first_slot: u64,
+    #[serde(default = "QmpBus::default_last_slot")]
+    pub last_slot: u64,
+}
+impl QmpBus {
+    fn default_first_slot() -> u64 {
+        0x10
+    }
+    fn default_last_slot() -> u64 {
+        0x1f
+    }
+}
+impl Default for QmpBus {
+    fn default() -> Self {
+        QmpBus {
+            id: "pci.0".to_owned(),
+            first_slot: QmpBus::default_first_slot(),
+            last_slot: QmpBus::default_last_slot(),
         }
+    }
+}
 
-        match self.domain.detach_device(&self.xml) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(
-                if e.code == libvirt::VIR_ERR_OPERATION_FAILED &&
-                e.message == "operation failed: matching input device not found" {
-                Error::BadState("Device not attached!")
-            } else {
-                e.into()
-            })
+/// Stable QEMU device id for an evdev, valid as a QOM path component
+pub fn qmp_device_id(evdev: &str) -> String {
+    let mut id = String::from("vfio-motion");
+    id.extend(evdev.chars().map(|c| match c {
+        '/' => '.',
+        c if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' => c,
+        _ => '-',
+    }));
+    // same as `domain_xml::alias()`, sanitising alone can map different paths to one id
+    id.push('-');
+    id.push_str(&domain_xml::short_hash(evdev));
+    id
+}
+/// `device_add` for a virtio-input-host device passing `evdev` through
+pub fn qmp_device_add(id: &str, evdev: &str, bus: &QmpBus, slot: u64) -> String {
+    json!({
+        "execute": "device_add",
+        "arguments": {
+            "driver": "virtio-input-host-pci",
+            "id": id,
+            "evdev": evdev,
+            "bus": bus.id,
+            "addr": format!("{:#x}", slot),
+        }
+    }).to_string()
+}
+pub fn qmp_device_del(id: &str) -> String {
+    json!({
+        "execute": "device_del",
+        "arguments": { "id": id }
+    }).to_string()
+}
+
+fn qmp_pci_devices<'a>(devices: &'a ::serde_json::Value, out: &mut Vec<&'a ::serde_json::Value>) {
+    if let Some(devices) = devices.as_array() {
+        for dev in devices {
+            out.push(dev);
+            qmp_pci_devices(&dev["pci_bridge"]["devices"], out);
+        }
+    }
+}
+fn qmp_all_devices(pci: &::serde_json::Value) -> Vec<&::serde_json::Value> {
+    let mut devices = Vec::new();
+    if let Some(buses) = pci.as_array() {
+        for bus in buses {
+            qmp_pci_devices(&bus["devices"], &mut devices);
         }
     }
+    devices
 }
-impl<'de> Deserialize<'de> for NativeDevice {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        #[serde(field_identifier, rename_all = "lowercase")]
-        enum Field { Evdev, Domain }
+/// The first free slot on `bus` going by a `query-pci` reply, `None` if there isn't one or the bus doesn't exist
+pub fn qmp_free_slot(pci: &::serde_json::Value, bus: &QmpBus) -> Option<u64> {
+    let devices = qmp_all_devices(pci);
+    // root buses aren't devices, anything else is the secondary bus of a bridge or root port
+    let number = match bus.id.as_str() {
+        "pci.0" | "pcie.0" => 0,
+        id => devices.iter()
+            .find(|d| d["qdev_id"] == id)
+            .and_then(|d| d["pci_bridge"]["bus"]["secondary"].as_u64())?,
+    };
 
-        struct DeviceVisitor;
-        impl<'de> Visitor<'de> for DeviceVisitor {
-            type Value = NativeDevice;
+    let used: Vec<u64> = devices.iter()
+        .filter(|d| d["bus"] == number)
+        .filter_map(|d| d["slot"].as_u64())
+        .collect();
+    trace!("used slots on {}: {:?}", bus.id, used);
+    (bus.first_slot..bus.last_slot + 1).find(|s| !used.contains(s))
+}
+/// Whether a `query-pci` reply has a device with the QEMU id `id`
+pub fn qmp_plugged(pci: &::serde_json::Value, id: &str) -> bool {
+    qmp_all_devices(pci).iter().any(|d| d["qdev_id"] == id)
+}
 
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("struct NativeDevice")
-            }
+#[derive(Clone)]
+pub struct NativeQmpDevice {
+    evdev: String,
+    /// What `evdev` resolved to when it was checked
+    path: String,
+    domain: Arc<DomainOps>,
 
-            fn visit_map<V>(self, mut map: V) -> Result<NativeDevice, V::Error>
-            where
-                V: MapAccess<'de>,
-            {
-                let mut evdev = None;
-                let mut domain = None;
-                while let Some(key) = map.next_key()? {
-                    match key {
-                        Field::Evdev => {
-                            if evdev.is_some() {
-                                return Err(de::Error::duplicate_field("evdev"));
-                            }
-                            evdev = Some(map.next_value()?);
-                        }
-                        Field::Domain => {
-                            if domain.is_some() {
-                                return Err(de::Error::duplicate_field("domain"));
-                            }
-
-                            domain = match get_native_global_conn() {
-                                None => return Err(de::Error::custom(Error::GlobalConnNotOpen)),
-                                Some(ref conn) => Some(::virt::domain::Domain::lookup_by_name(&conn, map.next_value()?).map_err(de::Error::custom)?.into())
-                            }
-                        }
-                    }
-                }
+    domain_name: String,
+    id: String,
+    bus: QmpBus,
+}
 
-                let evdev = evdev.ok_or_else(|| de::Error::missing_field("evdev"))?;
-                let domain = domain.ok_or_else(|| de::Error::missing_field("domain"))?;
-                NativeDevice::new(domain, evdev).map_err(de::Error::custom)
-            }
+impl NativeQmpDevice {
+    pub fn new(domain: Arc<DomainOps>, evdev: String, policy: &EvdevPolicy, bus: &QmpBus) -> Result<Self, Error> {
+        let path = policy.check(&evdev)?.to_string_lossy().into_owned();
+        let domain_name = domain.name()?;
+        let id = qmp_device_id(&evdev);
+        Ok(NativeQmpDevice {
+            evdev,
+            path,
+            domain,
+            domain_name,
+            id,
+            bus: bus.clone(),
+        })
+    }
+
+    fn query_pci(&self) -> Result<::serde_json::Value, Error> {
+        self.domain.qmp(r#"{"execute":"query-pci"}"#)
+    }
+}
+
+impl Device for NativeQmpDevice {
+    fn evdev(&self) -> &str {
+        &self.evdev
+    }
+    fn domain(&self) -> &str {
+        &self.domain_name
+    }
+
+    fn status(&self) -> Status {
+        match self.query_pci() {
+            Ok(pci) => qmp_plugged(&pci, &self.id).into(),
+            Err(e) => Status::Unknown(e)
+        }
+    }
+
+    fn attach(&self) -> Result<(), Error> {
+        let pci = self.query_pci()?;
+        if qmp_plugged(&pci, &self.id) {
+            return Err(Error::AlreadyAttached(self.evdev.clone()));
         }
 
-        deserializer.deserialize_struct("NativeDevice", &["evdev", "domain"], DeviceVisitor)
+        let addr = qmp_free_slot(&pci, &self.bus).ok_or(Error::BadState("No free PCI slots for hotplug!"))?;
+        debug!("hotplugging '{}' as '{}' at {} slot {:#x}", self.evdev, self.id, self.bus.id, addr);
+        self.domain.qmp(&qmp_device_add(&self.id, &self.path, &self.bus, addr))?;
+        Ok(())
+    }
+    fn detach(&self) -> Result<(), Error> {
+        if !qmp_plugged(&self.query_pci()?, &self.id) {
+            return Err(Error::NotAttached(self.evdev.clone()));
+        }
+
+        self.domain.qmp(&qmp_device_del(&self.id))?;
+        Ok(())
     }
 }
 
-#[cfg(target_os = "windows")]
+#[cfg(feature = "http")]
 #[derive(Deserialize)]
-struct HttpDeviceStatus {
-    attached: bool
+#[serde(tag = "status", rename_all = "lowercase")]
+enum HttpDeviceStatus {
+    Attached,
+    Detached,
+    Unknown {
+        message: String
+    },
 }
 
-#[cfg(target_os = "windows")]
-#[derive(Serialize)]
-pub struct HttpDevice<'a> {
+#[cfg(feature = "http")]
+#[derive(Clone, Serialize)]
+pub struct HttpDevice {
     #[serde(skip)]
-    client: &'a reqwest::Client,
+    client: reqwest::Client,
     #[serde(skip)]
     url: String,
 
-    domain: &'a str,
-    evdev: &'a str,
+    domain: String,
+    evdev: String,
 }
-#[cfg(target_os = "windows")]
-impl<'a> HttpDevice<'a> {
-    pub fn new(client: &'a reqwest::Client, host: &'a str, domain: &'a str, evdev: &'a str) -> HttpDevice<'a> {
+#[cfg(feature = "http")]
+impl HttpDevice {
+    pub fn new(client: reqwest::Client, host: &str, domain: &str, evdev: &str) -> HttpDevice {
         HttpDevice {
             client,
             url: format!("{}/device", host),
 
-            domain,
-            evdev,
+            domain: domain.to_owned(),
+            evdev: evdev.to_owned(),
         }
     }
 }
-#[cfg(target_os = "windows")]
-impl<'a> Device for HttpDevice<'a> {
+#[cfg(feature = "http")]
+impl Device for HttpDevice {
     fn domain(&self) -> &str {
         &self.domain
     }
@@ -348,21 +1643,28 @@ impl<'a> Device for HttpDevice<'a> {
         &self.evdev
     }
 
-    fn attached(&self) -> bool {
-        match self.client
+    fn status(&self) -> Status {
+        let mut res = match self.client
             .post(&format!("{}/status", self.url))
             .json(self)
             .send() {
-            Ok(mut res) => match res.json::<HttpDeviceStatus>() {
-                Ok(s) => s.attached,
-                Err(_) => false
-            },
-            Err(_) => false
+            Ok(res) => res,
+            Err(e) => return Status::Unknown(Error::Reqwest(e.to_string()))
+        };
+        if !res.status().is_success() {
+            return Status::Unknown(http_error(&mut res));
+        }
+
+        match res.json::<HttpDeviceStatus>() {
+            Ok(HttpDeviceStatus::Attached) => Status::Attached,
+            Ok(HttpDeviceStatus::Detached) => Status::Detached,
+            Ok(HttpDeviceStatus::Unknown { message }) => Status::Unknown(Error::StatusUnknown(message)),
+            Err(e) => Status::Unknown(Error::HttpDecode(e.to_string()))
         }
     }
 
     fn attach(&self) -> Result<(), Error> {
-        if self.attached() {
+        if self.status().is_attached() {
             warn!("device at '{}' is already attached", self.evdev);
         }
 
@@ -372,13 +1674,13 @@ impl<'a> Device for HttpDevice<'a> {
             .send()
             .map_err(|e| Error::Reqwest(e.to_string()))?;
         if !res.status().is_success() {
-            return Err(Error::Reqwest(res.text().unwrap_or(String::from("failed to decode response"))));
+            return Err(http_error(&mut res));
         }
 
         Ok(())
     }
     fn detach(&self) -> Result<(), Error> {
-        if !self.attached() {
+        if let Status::Detached = self.status() {
             warn!("device at '{}' is already detached", self.evdev);
         }
 
@@ -388,9 +1690,67 @@ impl<'a> Device for HttpDevice<'a> {
             .send()
             .map_err(|e| Error::Reqwest(e.to_string()))?;
         if !res.status().is_success() {
-            return Err(Error::Reqwest(res.text().unwrap_or(String::from("failed to decode response"))));
+            return Err(http_error(&mut res));
         }
 
         Ok(())
     }
 }
+
+#[cfg(feature = "http")]
+#[derive(Deserialize)]
+struct HttpGroupStatus {
+    attached: bool
+}
+
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub struct HttpGroup {
+    client: reqwest::Client,
+    url: String,
+
+    domain: String,
+    evdevs: Vec<String>,
+}
+#[cfg(feature = "http")]
+impl HttpGroup {
+    pub fn new(client: reqwest::Client, host: &str, domain: &str, evdevs: &[String]) -> HttpGroup {
+        HttpGroup {
+            client,
+            url: format!("{}/group", host),
+
+            domain: domain.to_owned(),
+            evdevs: evdevs.to_vec(),
+        }
+    }
+}
+#[cfg(feature = "http")]
+impl Group for HttpGroup {
+    fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn switch(&self, target: Target) -> Result<bool, Error> {
+        let req = GroupRequest {
+            domain: self.domain.clone(),
+            evdevs: self.evdevs.clone(),
+            target,
+            kind: None,
+            hotplug: None,
+            persistence: None,
+        };
+
+        let mut res = self.client
+            .post(&self.url)
+            .json(&req)
+            .send()
+            .map_err(|e| Error::Reqwest(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(http_error(&mut res));
+        }
+
+        res.json::<HttpGroupStatus>()
+            .map(|s| s.attached)
+            .map_err(|e| Error::HttpDecode(e.to_string()))
+    }
+}
@@ -6,16 +6,37 @@ extern crate log;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+#[macro_use]
+extern crate lazy_static;
 
 extern crate config;
 extern crate serde;
+#[cfg(feature = "native")]
 extern crate virt;
 #[cfg(target_os = "linux")]
+#[macro_use]
 extern crate nix;
 extern crate libc;
-#[cfg(target_os = "windows")]
+extern crate xml;
+extern crate glob;
+extern crate futures;
+extern crate futures_cpupool;
+#[cfg(feature = "http")]
 extern crate reqwest;
 
 pub mod util;
 pub mod libvirt;
+#[cfg(feature = "native")]
+pub mod supervisor;
+pub mod domain_xml;
+pub mod evdev;
 pub mod input;
+pub mod qmp;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod async_input;
+pub mod mock;
+#[cfg(feature = "server")]
+pub mod watch;
+#[cfg(feature = "server")]
+pub mod lifecycle;
@@ -6,6 +6,7 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+#[cfg(feature = "native")]
 macro_rules! c_chars_to_string {
     ($x:expr) => {{
         let ret = ::std::ffi::CStr::from_ptr($x).to_string_lossy().into_owned();
@@ -18,18 +19,37 @@ macro_rules! c_chars_to_string {
     }};
 
 }
-macro_rules! string_to_c_chars {
-    ($x:expr) => (::std::ffi::CString::new($x).unwrap().as_ptr())
-}
 
-use std::ops::{Deref, DerefMut};
+#[cfg(feature = "native")]
+use std::ops::Deref;
+#[cfg(feature = "native")]
+use std::ffi::CString;
+#[cfg(feature = "native")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "native")]
+use std::thread;
+#[cfg(feature = "native")]
+use std::cmp;
+#[cfg(feature = "native")]
+use std::time::Duration;
+#[cfg(feature = "native")]
 use std::ptr;
-use std::mem;
 
+#[cfg(feature = "native")]
 use ::virt::domain::sys::virDomainPtr;
-use ::libc::{c_uint, c_int, c_char, c_void};
+#[cfg(feature = "native")]
+use ::virt::connect::sys::virConnectPtr;
+use ::libc::{c_uint, c_int};
+#[cfg(feature = "native")]
+use ::libc::{c_char, c_void};
+#[cfg(feature = "native")]
 use ::serde::ser::{self, Serialize, Serializer};
+#[cfg(feature = "native")]
+use ::serde::de::DeserializeOwned;
+
+pub use ::qmp::QmpError;
 
+#[cfg(feature = "native")]
 quick_error! {
     #[derive(Debug)]
     pub enum Error {
@@ -37,90 +57,311 @@ quick_error! {
             from()
             description(err.description())
         }
-        GlobalConnNotOpen
         Virt(err: ::virt::error::Error) {
             from()
             description(err.description())
         }
-        QemuMonitor(msg: ::serde_json::Value) {
-            display("Qemu Monitor command error: {:#?}", msg)
+        Nul(err: ::std::ffi::NulError) {
+            from()
+            description(err.description())
+        }
+        QemuMonitor(err: QmpError) {
+            display("QEMU monitor error ({}): {}", err.class, err.desc)
+        }
+        EmptyReply {
+            description("QEMU monitor sent an empty reply")
         }
     }
 }
 
+#[cfg(feature = "native")]
 pub struct Connection(::virt::connect::Connect);
+// libvirt has been thread-safe since 0.6.0: a `virConnectPtr` may be used from several threads at once,
+// with the driver serialising access internally, and the "last error" it reports is thread-local (which
+// is what `virt::error::Error::new()` reads). The only thing we must not do is close it while another
+// thread is still using it, which `Drop` (rather than an exposed `close()`) plus `Arc` rules out.
+#[cfg(feature = "native")]
 unsafe impl Send for Connection {}
+#[cfg(feature = "native")]
 unsafe impl Sync for Connection {}
 
+#[cfg(feature = "native")]
 impl Drop for Connection {
     fn drop(&mut self) {
         trace!("closing qemu connection");
-        self.close().unwrap();
+        // a connection that died with libvirtd can fail to close, there's nothing left to clean up anyway
+        if let Err(e) = self.0.close() {
+            warn!("failed to close libvirt connection: {}", e);
+        }
     }
 }
+#[cfg(feature = "native")]
 impl Deref for Connection {
     type Target = ::virt::connect::Connect;
     fn deref(&self) -> &::virt::connect::Connect {
         &self.0
     }
 }
-impl DerefMut for Connection {
-    fn deref_mut(&mut self) -> &mut ::virt::connect::Connect {
-        &mut self.0
-    }
-}
 
+#[cfg(feature = "native")]
 impl Connection {
     pub fn open(uri: &str) -> Result<Connection, ::virt::error::Error> {
         Ok(Connection(::virt::connect::Connect::open(uri)?))
     }
+
+    /// Call `callback` with the domain name whenever any domain's lifecycle changes, needs `spawn_event_loop()`.
+    /// Returns an id for `deregister_event()`.
+    pub fn register_lifecycle_event(&self, callback: LifecycleCallback) -> Result<i32, Error> {
+        let opaque = Box::into_raw(Box::new(callback)) as *mut c_void;
+        let id = unsafe {
+            virConnectDomainEventRegisterAny(self.0.as_ptr(), ptr::null_mut(), VIR_DOMAIN_EVENT_ID_LIFECYCLE,
+                                             _lifecycle_callback, opaque, _free_lifecycle_callback)
+        };
+        if id == -1 {
+            unsafe { _free_lifecycle_callback(opaque); }
+            return Err(::virt::error::Error::new().into());
+        }
+        Ok(id)
+    }
+    pub fn deregister_event(&self, id: i32) -> Result<(), Error> {
+        if unsafe { virConnectDomainEventDeregisterAny(self.0.as_ptr(), id) } == -1 {
+            return Err(::virt::error::Error::new().into());
+        }
+        Ok(())
+    }
 }
 
+// Values from libvirt's headers, also used without the `native` feature (e.g. by `remote`)
+
+// virErrorNumber
 pub const VIR_ERR_INTERNAL_ERROR: i32 = 1;
+pub const VIR_ERR_NO_CONNECT: i32 = 5;
+pub const VIR_ERR_INVALID_CONN: i32 = 6;
 pub const VIR_ERR_OPERATION_FAILED: i32 = 9;
+pub const VIR_ERR_NO_DEVICE: i32 = 23;
+pub const VIR_ERR_OPERATION_DENIED: i32 = 29;
+pub const VIR_ERR_SYSTEM_ERROR: i32 = 38;
+pub const VIR_ERR_RPC: i32 = 39;
+pub const VIR_ERR_NO_DOMAIN: i32 = 42;
+pub const VIR_ERR_AUTH_FAILED: i32 = 45;
+pub const VIR_ERR_OPERATION_INVALID: i32 = 55;
+pub const VIR_ERR_AUTH_CANCELLED: i32 = 79;
+pub const VIR_ERR_RESOURCE_BUSY: i32 = 87;
+pub const VIR_ERR_ACCESS_DENIED: i32 = 88;
+pub const VIR_ERR_AUTH_UNAVAILABLE: i32 = 94;
+pub const VIR_ERR_DEVICE_MISSING: i32 = 99;
+
+// virErrorDomain
+pub const VIR_FROM_RPC: i32 = 7;
+pub const VIR_FROM_QEMU: i32 = 10;
+pub const VIR_FROM_REMOTE: i32 = 13;
+
+// virDomainState
+pub const VIR_DOMAIN_PAUSED: c_uint = 3;
+
+pub const VIR_DOMAIN_NONE: c_uint = 0;
+// virDomainModificationImpact
+pub const VIR_DOMAIN_AFFECT_LIVE: c_uint = 1;
+pub const VIR_DOMAIN_AFFECT_CONFIG: c_uint = 2;
+// virDomainXMLFlags
+pub const VIR_DOMAIN_XML_INACTIVE: c_uint = 2;
 
 pub type QemuMonitorCommandFlags = c_uint;
 pub const VIR_DOMAIN_QEMU_MONITOR_COMMAND_DEFAULT: QemuMonitorCommandFlags = 0;
 pub const VIR_DOMAIN_QEMU_MONITOR_COMMAND_HMP: QemuMonitorCommandFlags = 1;
 
+#[cfg(feature = "native")]
 pub type virErrorFunc = unsafe extern "C" fn(*mut c_void, ::virt::error::sys::virErrorPtr);
+#[cfg(feature = "native")]
 #[link(name = "virt-qemu")]
 extern "C" {
-    fn virSetErrorFunc(ctx: *mut c_void, handler: virErrorFunc);
     fn virDomainQemuMonitorCommand(ptr: virDomainPtr, cmd: *const c_char, result: *mut *mut c_char, flags: c_uint) -> c_int;
 }
+#[cfg(feature = "native")]
+type virConnectDomainEventLifecycleCallback =
+    unsafe extern "C" fn(virConnectPtr, virDomainPtr, c_int, c_int, *mut c_void) -> c_int;
+#[cfg(feature = "native")]
+type virFreeCallback = unsafe extern "C" fn(*mut c_void);
+#[cfg(feature = "native")]
+#[link(name = "virt")]
+extern "C" {
+    fn virSetErrorFunc(ctx: *mut c_void, handler: virErrorFunc);
+    fn virDomainDetachDeviceAlias(ptr: virDomainPtr, alias: *const c_char, flags: c_uint) -> c_int;
+    fn virDomainGetName(ptr: virDomainPtr) -> *const c_char;
 
-pub type VirtErrorHandler<T> = fn(Box<Option<T>>, ::virt::error::Error);
-struct VirtErrorData<T> {
-    handler: VirtErrorHandler<T>,
-    ctx: Box<Option<T>>
+    fn virEventRegisterDefaultImpl() -> c_int;
+    fn virEventRunDefaultImpl() -> c_int;
+    // the real signature takes a generic callback, which is cast based on `eventID`
+    fn virConnectDomainEventRegisterAny(conn: virConnectPtr, dom: virDomainPtr, eventID: c_int,
+                                        cb: virConnectDomainEventLifecycleCallback, opaque: *mut c_void,
+                                        freecb: virFreeCallback) -> c_int;
+    fn virConnectDomainEventDeregisterAny(conn: virConnectPtr, callbackID: c_int) -> c_int;
 }
-unsafe extern "C" fn _error_handler<T>(_ctx: *mut c_void, _err: ::virt::error::sys::virErrorPtr) {
-    let _ctx: Box<VirtErrorData<T>> = mem::transmute(_ctx);
-    let err = ::virt::error::Error {
-        code: (*_err).code,
-        domain: (*_err).domain,
-        message: c_chars_to_string!((*_err).message, nofree),
-        level: ::virt::error::ErrorLevel::from((*_err).level)
-    };
 
-    (_ctx.handler)(_ctx.ctx, err);
+// virDomainEventID
+#[cfg(feature = "native")]
+const VIR_DOMAIN_EVENT_ID_LIFECYCLE: c_int = 0;
+
+/// virDomainEventType, what happened to a domain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    Defined,
+    Undefined,
+    Started,
+    Suspended,
+    Resumed,
+    Stopped,
+    Shutdown,
+    PmSuspended,
+    Crashed,
+    Other(i32),
+}
+impl From<c_int> for LifecycleEvent {
+    fn from(event: c_int) -> Self {
+        match event {
+            0 => LifecycleEvent::Defined,
+            1 => LifecycleEvent::Undefined,
+            2 => LifecycleEvent::Started,
+            3 => LifecycleEvent::Suspended,
+            4 => LifecycleEvent::Resumed,
+            5 => LifecycleEvent::Stopped,
+            6 => LifecycleEvent::Shutdown,
+            7 => LifecycleEvent::PmSuspended,
+            8 => LifecycleEvent::Crashed,
+            e => LifecycleEvent::Other(e),
+        }
+    }
+}
+pub type LifecycleCallback = Box<Fn(&str, LifecycleEvent) + Send + Sync>;
+
+#[cfg(feature = "native")]
+unsafe extern "C" fn _lifecycle_callback(_conn: virConnectPtr, dom: virDomainPtr, event: c_int, _detail: c_int, opaque: *mut c_void) -> c_int {
+    let callback = &*(opaque as *const LifecycleCallback);
+    // owned by the domain, valid for the duration of the callback
+    let name = virDomainGetName(dom);
+    if name.is_null() {
+        return 0;
+    }
+    let name = c_chars_to_string!(name, nofree);
+    callback(&name, LifecycleEvent::from(event));
+    0
+}
+#[cfg(feature = "native")]
+unsafe extern "C" fn _free_lifecycle_callback(opaque: *mut c_void) {
+    drop(Box::from_raw(opaque as *mut LifecycleCallback));
+}
+
+/// How long the event loop waits after an iteration fails, doubling each time it fails again
+#[cfg(feature = "native")]
+pub const EVENT_LOOP_MIN_BACKOFF: Duration = Duration::from_millis(100);
+#[cfg(feature = "native")]
+pub const EVENT_LOOP_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "native")]
+lazy_static! {
+    static ref EVENT_LOOP: Mutex<bool> = Mutex::new(false);
 }
-pub fn set_error_handler<T>(ctx: Box<Option<T>>, handler: VirtErrorHandler<T>) {
+
+/// Register libvirt's default event loop and run it on a background thread. Must be called before opening
+/// any connection that events are wanted from, only the first successful call does anything.
+#[cfg(feature = "native")]
+pub fn spawn_event_loop() -> Result<(), Error> {
+    let mut running = EVENT_LOOP.lock().unwrap();
+    if *running {
+        return Ok(());
+    }
     unsafe {
-        let ctx = Box::new(VirtErrorData {
-            handler,
-            ctx
-        });
+        if virEventRegisterDefaultImpl() == -1 {
+            return Err(::virt::error::Error::new().into());
+        }
+    }
+    *running = true;
 
-        virSetErrorFunc(mem::transmute(ctx), _error_handler::<T>);
+    thread::spawn(|| {
+        let mut backoff = EVENT_LOOP_MIN_BACKOFF;
+        loop {
+            if unsafe { virEventRunDefaultImpl() } == -1 {
+                error!("libvirt event loop failed, retrying in {:?}: {}", backoff, ::virt::error::Error::new());
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, EVENT_LOOP_MAX_BACKOFF);
+            } else {
+                backoff = EVENT_LOOP_MIN_BACKOFF;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "native")]
+pub type ErrorSink = Box<Fn(::virt::error::Error) + Send + Sync>;
+
+#[cfg(feature = "native")]
+unsafe extern "C" fn _error_sink(ctx: *mut c_void, err: ::virt::error::sys::virErrorPtr) {
+    if ctx.is_null() || err.is_null() {
+        return;
+    }
+
+    let sink = &*(ctx as *const ErrorSink);
+    let message = if (*err).message.is_null() {
+        String::new()
+    } else {
+        c_chars_to_string!((*err).message, nofree)
+    };
+    sink(::virt::error::Error {
+        code: (*err).code,
+        domain: (*err).domain,
+        message,
+        level: ::virt::error::ErrorLevel::from((*err).level)
+    });
+}
+/// Send every libvirt error to `sink` instead of stderr. libvirt may call the sink from any thread, at any
+/// time, so it's kept for the rest of the process (replacing it leaks the old one).
+#[cfg(feature = "native")]
+pub fn set_error_sink<F: Fn(::virt::error::Error) + Send + Sync + 'static>(sink: F) {
+    let sink: Box<ErrorSink> = Box::new(Box::new(sink));
+    unsafe {
+        virSetErrorFunc(Box::into_raw(sink) as *mut c_void, _error_sink);
+    }
+}
+
+#[cfg(feature = "native")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QmpReply<T> {
+    // must come first, a missing `return` would deserialize as null
+    Error { error: QmpError },
+    Return {
+        #[serde(rename = "return")]
+        value: T
+    },
+}
+
+/// A QMP reply as `monitor_command()` returns it, which is empty if libvirt got nothing back (e.g. QEMU died
+/// mid-command)
+#[cfg(feature = "native")]
+fn parse_qmp<T: DeserializeOwned>(reply: &str) -> Result<T, Error> {
+    if reply.trim().is_empty() {
+        return Err(Error::EmptyReply);
+    }
+    match ::serde_json::from_str(reply)? {
+        QmpReply::Return { value } => Ok(value),
+        QmpReply::Error { error } => Err(Error::QemuMonitor(error)),
     }
 }
 
-pub struct Domain(::virt::domain::Domain);
+/// Cheaply clonable handle to a libvirt domain
+#[cfg(feature = "native")]
+#[derive(Clone)]
+pub struct Domain(Arc<::virt::domain::Domain>);
+// `virDomainPtr`s are reference counted objects that hold their own reference to the connection, so they
+// stay valid for as long as we hold one. All libvirt calls on them go through the (thread-safe, see
+// `Connection`) connection they were looked up on.
+#[cfg(feature = "native")]
 unsafe impl Send for Domain {}
+#[cfg(feature = "native")]
 unsafe impl Sync for Domain {}
 
+#[cfg(feature = "native")]
 impl Deref for Domain {
     type Target = ::virt::domain::Domain;
     fn deref(&self) -> &::virt::domain::Domain {
@@ -128,40 +369,48 @@ impl Deref for Domain {
     }
 }
 
+#[cfg(feature = "native")]
 impl From<::virt::domain::Domain> for Domain {
     fn from(d: ::virt::domain::Domain) -> Self {
-        Domain(d)
+        Domain(Arc::new(d))
     }
 }
+#[cfg(feature = "native")]
 impl Domain {
-    pub fn qemu_monitor_command(&self, command: &str, flags: QemuMonitorCommandFlags) -> Result<Option<::serde_json::Value>, Error> {
+    /// Detach the device with `alias` (needs libvirt 4.4.0)
+    pub fn detach_device_alias(&self, alias: &str, flags: c_uint) -> Result<(), Error> {
+        let alias = CString::new(alias)?;
+        unsafe {
+            if virDomainDetachDeviceAlias(self.0.as_ptr(), alias.as_ptr(), flags) == -1 {
+                return Err(::virt::error::Error::new().into());
+            }
+        }
+        Ok(())
+    }
+    /// Send a raw command to QEMU's monitor, returning the raw reply
+    pub fn monitor_command(&self, command: &str, flags: QemuMonitorCommandFlags) -> Result<String, Error> {
+        let command = CString::new(command)?;
         unsafe {
             let mut result = ptr::null_mut();
-            let ret = virDomainQemuMonitorCommand(self.0.as_ptr(), string_to_c_chars!(command), &mut result, flags);
+            let ret = virDomainQemuMonitorCommand(self.0.as_ptr(), command.as_ptr(), &mut result, flags);
             trace!("qemu monitor ret: {}", ret);
 
             if ret != 0 {
                 return Err(::virt::error::Error::new().into());
             }
-
-            Ok(if !result.is_null() {
-                let msg = c_chars_to_string!(result);
-                let parse: Result<::serde_json::Value, ::serde_json::Error> = ::serde_json::from_str(&msg);
-                Some(if let Ok(val) = parse {
-                    if val.as_object().unwrap().contains_key("error") {
-                        return Err(Error::QemuMonitor(val.as_object().unwrap()["error"].clone()));
-                    } else {
-                        val
-                    }
-                } else {
-                    json!(msg)
-                })
-            } else {
-               None
-            })
+            Ok(if result.is_null() { String::new() } else { c_chars_to_string!(result) })
         }
     }
+    /// Run a QMP command, deserializing what it returns
+    pub fn qmp<T: DeserializeOwned>(&self, command: &str) -> Result<T, Error> {
+        parse_qmp(&self.monitor_command(command, VIR_DOMAIN_QEMU_MONITOR_COMMAND_DEFAULT)?)
+    }
+    /// Run a human monitor command, returning its output
+    pub fn hmp(&self, command: &str) -> Result<String, Error> {
+        self.monitor_command(command, VIR_DOMAIN_QEMU_MONITOR_COMMAND_HMP)
+    }
 }
+#[cfg(feature = "native")]
 impl Serialize for Domain {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -171,3 +420,59 @@ impl Serialize for Domain {
         serializer.serialize_str(&name)
     }
 }
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use ::serde_json::Value;
+
+    use super::*;
+
+    const TEST_URI: &'static str = "test:///default";
+
+    #[test]
+    fn error_sink_gets_errors() {
+        let codes = Arc::new(Mutex::new(Vec::new()));
+        let sink_codes = codes.clone();
+        set_error_sink(move |err| sink_codes.lock().unwrap().push(err.code));
+
+        let conn = Connection::open(TEST_URI).unwrap();
+        assert!(::virt::domain::Domain::lookup_by_name(&conn, "vfio-motion-missing").is_err());
+        assert!(codes.lock().unwrap().contains(&VIR_ERR_NO_DOMAIN));
+    }
+
+    #[test]
+    fn connection_drops_cleanly() {
+        let conn = Connection::open(TEST_URI).unwrap();
+        let domain = Domain::from(::virt::domain::Domain::lookup_by_name(&conn, "test").unwrap());
+        drop(conn);
+        // the domain keeps its own reference to the connection
+        assert_eq!(domain.get_name().unwrap(), "test");
+    }
+
+    #[test]
+    fn qmp_return() {
+        let value: Value = parse_qmp(r#"{"return":{},"id":"libvirt-1"}"#).unwrap();
+        assert_eq!(value, json!({}));
+    }
+
+    #[test]
+    fn qmp_error() {
+        match parse_qmp::<Value>(r#"{"error":{"class":"DeviceNotFound","desc":"Device 'x' not found"}}"#) {
+            Err(Error::QemuMonitor(e)) => {
+                assert_eq!(e.class, "DeviceNotFound");
+                assert_eq!(e.desc, "Device 'x' not found");
+            },
+            r => panic!("expected a monitor error, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn qmp_empty() {
+        match parse_qmp::<Value>("") {
+            Err(Error::EmptyReply) => {},
+            r => panic!("expected an empty reply error, got {:?}", r)
+        }
+    }
+}
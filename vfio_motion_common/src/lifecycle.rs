@@ -0,0 +1,186 @@
+//! Applying per-domain device policies as domains start, stop and pause. Only `DomainPolicy` is built without
+//! the `native` feature.
+#[cfg(feature = "native")]
+use std::thread;
+#[cfg(feature = "native")]
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(feature = "native")]
+use std::collections::HashMap;
+
+#[cfg(feature = "native")]
+use ::libvirt::{self, Connection, LifecycleEvent};
+use ::input::Hotplug;
+#[cfg(feature = "native")]
+use ::input::{Error, Persistence, Device, DeviceRequest, NativeInput};
+#[cfg(feature = "native")]
+use ::watch::Registry;
+
+/// What to do with a domain's devices when its state changes
+#[derive(Clone, Debug, Default)]
+pub struct DomainPolicy {
+    /// Device specifiers attached by `attach_on_start`
+    pub devices: Vec<String>,
+    pub hotplug: Hotplug,
+
+    pub attach_on_start: bool,
+    /// Also take devices out of the persistent definition when the domain shuts down or crashes, so the next
+    /// boot doesn't grab them again. The running domain gives them back to the host by itself.
+    pub release_on_stop: bool,
+    /// Give devices back to the host when the domain is paused, resuming it for as long as that takes. A guest
+    /// that's gone to sleep only has them taken out of its persistent definition.
+    pub release_on_pause: bool,
+    /// Resume a paused domain before attaching anything to it
+    pub resume: bool,
+}
+
+/// Applies `DomainPolicy`s, domains without one are left alone
+#[cfg(feature = "native")]
+#[derive(Clone)]
+pub struct Lifecycle {
+    input: NativeInput,
+    registry: Arc<Registry>,
+    policies: Arc<HashMap<String, DomainPolicy>>,
+}
+#[cfg(feature = "native")]
+impl Lifecycle {
+    pub fn new(input: NativeInput, registry: Arc<Registry>, policies: HashMap<String, DomainPolicy>) -> Lifecycle {
+        Lifecycle {
+            input,
+            registry,
+            policies: Arc::new(policies),
+        }
+    }
+
+    /// Subscribe to lifecycle events on the input's connection (and any it's replaced with), handling them on a
+    /// background thread so libvirt's event loop isn't held up by hotplugging
+    pub fn watch(&self) -> Result<thread::JoinHandle<()>, Error> {
+        let (tx, rx) = mpsc::channel();
+        let tx = Arc::new(Mutex::new(tx));
+        let register = move |conn: &Connection| {
+            let tx = tx.clone();
+            conn.register_lifecycle_event(Box::new(move |domain, event| {
+                let _ = tx.lock().unwrap().send((domain.to_owned(), event));
+            }))
+        };
+        let id = Arc::new(Mutex::new(register(&self.input.conn())?));
+
+        // a registered callback keeps its connection open, so it has to go before the old connection can
+        let supervisor = Arc::downgrade(self.input.supervisor());
+        self.input.supervisor().on_reconnect(Box::new(move |conn| {
+            let mut id = id.lock().unwrap();
+            if let Some(s) = supervisor.upgrade() {
+                if let Err(e) = s.get().deregister_event(*id) {
+                    debug!("failed to deregister lifecycle events on the old connection: {}", e);
+                }
+            }
+            match register(conn) {
+                Ok(new) => *id = new,
+                Err(e) => error!("failed to resubscribe to domain lifecycle events: {}", e)
+            }
+        }));
+
+        let lifecycle = self.clone();
+        Ok(thread::spawn(move || for (domain, event) in rx {
+            lifecycle.handle(&domain, event);
+        }))
+    }
+
+    pub fn handle(&self, domain: &str, event: LifecycleEvent) {
+        debug!("domain '{}': {:?}", domain, event);
+        let policy = self.policies.get(domain);
+        match event {
+            // the devices went with the domain whatever its policy, stop treating them as attached
+            LifecycleEvent::Stopped | LifecycleEvent::Shutdown | LifecycleEvent::Crashed => {
+                let released = self.registry.release(domain);
+                for (spec, hotplug) in released {
+                    if !policy.map_or(false, |p| p.release_on_stop) {
+                        debug!("domain '{}' stopped, forgot {:?}", domain, spec);
+                        continue;
+                    }
+
+                    match self.device(domain, &spec, hotplug, Some(Persistence::Config)).and_then(|d| d.detach()) {
+                        Ok(()) | Err(Error::NotAttached(_)) => info!("domain '{}' stopped, released {:?}", domain, spec),
+                        Err(e) => error!("domain '{}' stopped but {:?} couldn't be released: {}", domain, spec, e)
+                    }
+                }
+            },
+            _ => if let Some(policy) = policy {
+                self.apply(domain, event, policy);
+            }
+        }
+    }
+
+    fn apply(&self, domain: &str, event: LifecycleEvent, policy: &DomainPolicy) {
+        match event {
+            LifecycleEvent::Started if policy.attach_on_start => for spec in &policy.devices {
+                let hotplug = self.input.hotplug(spec, policy.hotplug);
+                match self.attach(domain, spec, hotplug) {
+                    Ok(()) => info!("domain '{}' started, attached {:?}", domain, spec),
+                    Err(Error::AlreadyAttached(_)) => self.registry.attached(domain, spec, hotplug),
+                    Err(e) => error!("domain '{}' started but {:?} couldn't be attached: {}", domain, spec, e)
+                }
+            },
+            LifecycleEvent::Suspended if policy.release_on_pause => if let Err(e) = self.release_paused(domain) {
+                error!("domain '{}' paused but its devices couldn't be released: {}", domain, e);
+            },
+            // a sleeping guest can't acknowledge an unplug and resuming doesn't wake it, only the next boot changes
+            LifecycleEvent::PmSuspended if policy.release_on_pause => for (spec, hotplug) in self.registry.get(domain) {
+                match self.device(domain, &spec, hotplug, Some(Persistence::Config)).and_then(|d| d.detach()) {
+                    Ok(()) | Err(Error::NotAttached(_)) =>
+                        info!("domain '{}' is asleep, released {:?} from its definition", domain, spec),
+                    Err(e) => error!("domain '{}' is asleep but {:?} couldn't be released: {}", domain, spec, e)
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Give a paused domain's devices back to the host. The guest has to acknowledge hot-unplugs, so it's resumed
+    /// for the detach and paused again after.
+    fn release_paused(&self, domain: &str) -> Result<(), Error> {
+        let dom = ::virt::domain::Domain::lookup_by_name(&self.input.conn(), domain)?;
+        dom.resume()?;
+        for (spec, hotplug) in self.registry.release(domain) {
+            match self.device(domain, &spec, hotplug, None).and_then(|d| d.detach()) {
+                Ok(()) => info!("domain '{}' paused, released {:?} to the host", domain, spec),
+                Err(e) => {
+                    error!("domain '{}' paused but {:?} couldn't be released: {}", domain, spec, e);
+                    self.registry.attached(domain, &spec, hotplug);
+                }
+            }
+        }
+        dom.suspend()?;
+        Ok(())
+    }
+
+    /// Resume `domain` if it's paused and its policy says to
+    pub fn before_attach(&self, domain: &str) -> Result<(), Error> {
+        if !self.policies.get(domain).map_or(false, |p| p.resume) {
+            return Ok(());
+        }
+
+        let dom = ::virt::domain::Domain::lookup_by_name(&self.input.conn(), domain)?;
+        if dom.get_state()?.0 == libvirt::VIR_DOMAIN_PAUSED {
+            info!("resuming domain '{}' before attaching", domain);
+            dom.resume()?;
+        }
+        Ok(())
+    }
+
+    fn device(&self, domain: &str, spec: &str, hotplug: Hotplug, persistence: Option<Persistence>)
+              -> Result<Arc<Device>, Error> {
+        self.input.request(&DeviceRequest {
+            domain: domain.to_owned(),
+            evdev: spec.to_owned(),
+            kind: None,
+            hotplug: Some(hotplug),
+            persistence,
+        }, hotplug)
+    }
+    fn attach(&self, domain: &str, spec: &str, hotplug: Hotplug) -> Result<(), Error> {
+        self.before_attach(domain)?;
+        self.device(domain, spec, hotplug, None)?.attach()?;
+        self.registry.attached(domain, spec, hotplug);
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! An in-memory backend with scriptable domains, latency and failures, for trying out the server and client
+//! without libvirt or real evdevs.
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+
+use ::evdev::EvdevInfo;
+use ::input::{Error, ErrorKind, Input, Domains, Device, Status};
+
+/// Operations failures can be injected into
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MockOp {
+    Domains,
+    Status,
+    Attach,
+    Detach,
+}
+
+#[derive(Default)]
+struct MockState {
+    domains: Vec<String>,
+    evdevs: Vec<EvdevInfo>,
+    /// (domain, evdev)
+    attached: HashSet<(String, String)>,
+    /// Keyed by evdev, empty for `MockOp::Domains`
+    failures: HashMap<(MockOp, String), ErrorKind>,
+    latency: Duration,
+}
+
+#[derive(Clone, Default)]
+pub struct MockInput(Arc<Mutex<MockState>>);
+impl MockInput {
+    pub fn new() -> MockInput {
+        Default::default()
+    }
+    pub fn domain(self, name: &str) -> MockInput {
+        self.0.lock().unwrap().domains.push(name.to_owned());
+        self
+    }
+    pub fn evdev(self, info: EvdevInfo) -> MockInput {
+        self.0.lock().unwrap().evdevs.push(info);
+        self
+    }
+    /// How long every operation takes
+    pub fn latency(self, latency: Duration) -> MockInput {
+        self.0.lock().unwrap().latency = latency;
+        self
+    }
+    pub fn attached(self, domain: &str, evdev: &str) -> MockInput {
+        self.0.lock().unwrap().attached.insert((domain.to_owned(), evdev.to_owned()));
+        self
+    }
+
+    /// Make `op` on `evdev` fail with `kind` until `heal()` is called
+    pub fn fail(&self, op: MockOp, evdev: &str, kind: ErrorKind) {
+        self.0.lock().unwrap().failures.insert((op, evdev.to_owned()), kind);
+    }
+    pub fn heal(&self) {
+        self.0.lock().unwrap().failures.clear();
+    }
+    pub fn is_attached(&self, domain: &str, evdev: &str) -> bool {
+        self.0.lock().unwrap().attached.contains(&(domain.to_owned(), evdev.to_owned()))
+    }
+
+    /// Wait out the latency, then fail if `op` has been told to
+    fn op(&self, op: MockOp, evdev: &str) -> Result<(), Error> {
+        let latency = self.0.lock().unwrap().latency;
+        thread::sleep(latency);
+
+        match self.0.lock().unwrap().failures.get(&(op, evdev.to_owned())) {
+            Some(&kind) => Err(Error::Remote(kind, format!("injected {:?} failure for {:?}", op, evdev), Vec::new())),
+            None => Ok(())
+        }
+    }
+}
+impl Input for MockInput {
+    fn domains(&self) -> Arc<Domains> {
+        Arc::new(MockDomains(self.clone()))
+    }
+    fn device(&self, domain: &str, evdev: &str) -> Result<Arc<Device>, Error> {
+        if !self.0.lock().unwrap().domains.iter().any(|d| d == domain) {
+            return Err(Error::Remote(ErrorKind::NoDomain, format!("no domain '{}'", domain), Vec::new()));
+        }
+
+        Ok(Arc::new(MockDevice {
+            input: self.clone(),
+            domain: domain.to_owned(),
+            evdev: evdev.to_owned(),
+        }))
+    }
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, Error> {
+        Ok(self.0.lock().unwrap().evdevs.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct MockDomains(MockInput);
+impl Domains for MockDomains {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let input = &self.0;
+        input.op(MockOp::Domains, "")?;
+        Ok(input.0.lock().unwrap().domains.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct MockDevice {
+    input: MockInput,
+    domain: String,
+    evdev: String,
+}
+impl Device for MockDevice {
+    fn evdev(&self) -> &str {
+        &self.evdev
+    }
+    fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn status(&self) -> Status {
+        match self.input.op(MockOp::Status, &self.evdev) {
+            Ok(()) => self.input.is_attached(&self.domain, &self.evdev).into(),
+            Err(e) => Status::Unknown(e)
+        }
+    }
+
+    fn attach(&self) -> Result<(), Error> {
+        self.input.op(MockOp::Attach, &self.evdev)?;
+        let mut state = self.input.0.lock().unwrap();
+        if state.attached.iter().any(|&(_, ref e)| *e == self.evdev) {
+            return Err(Error::AlreadyAttached(self.evdev.clone()));
+        }
+        state.attached.insert((self.domain.clone(), self.evdev.clone()));
+        Ok(())
+    }
+    fn detach(&self) -> Result<(), Error> {
+        self.input.op(MockOp::Detach, &self.evdev)?;
+        if !self.input.0.lock().unwrap().attached.remove(&(self.domain.clone(), self.evdev.clone())) {
+            return Err(Error::NotAttached(self.evdev.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::input::{DeviceGroup, Group, Target, move_group};
+
+    const EVDEVS: [&'static str; 3] = ["/dev/input/event0", "/dev/input/event1", "/dev/input/event2"];
+
+    fn group(input: &MockInput, domain: &str) -> DeviceGroup {
+        let devices = EVDEVS.iter()
+            .map(|e| input.device(domain, e).unwrap())
+            .collect();
+        DeviceGroup::new(domain, devices).settle(Duration::from_millis(0))
+    }
+
+    #[test]
+    fn attach_detach() {
+        let input = MockInput::new().domain("a");
+        let device = input.device("a", EVDEVS[0]).unwrap();
+        assert!(match device.status() { Status::Detached => true, _ => false });
+
+        device.attach().unwrap();
+        assert!(input.is_attached("a", EVDEVS[0]));
+        assert!(match device.status() { Status::Attached => true, _ => false });
+        match device.attach() {
+            Err(Error::AlreadyAttached(ref e)) if e == EVDEVS[0] => {},
+            r => panic!("expected AlreadyAttached, got {:?}", r),
+        }
+
+        device.detach().unwrap();
+        assert!(!input.is_attached("a", EVDEVS[0]));
+        match device.detach() {
+            Err(Error::NotAttached(ref e)) if e == EVDEVS[0] => {},
+            r => panic!("expected NotAttached, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn attached_elsewhere() {
+        let input = MockInput::new().domain("a").domain("b").attached("b", EVDEVS[0]);
+        match input.device("a", EVDEVS[0]).unwrap().attach() {
+            Err(Error::AlreadyAttached(_)) => {},
+            r => panic!("expected AlreadyAttached, got {:?}", r),
+        }
+        assert!(input.is_attached("b", EVDEVS[0]));
+    }
+
+    #[test]
+    fn no_domain() {
+        match MockInput::new().device("a", EVDEVS[0]) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::NoDomain),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn injected_failure() {
+        let input = MockInput::new().domain("a");
+        input.fail(MockOp::Attach, EVDEVS[0], ErrorKind::Busy);
+        let device = input.device("a", EVDEVS[0]).unwrap();
+        assert_eq!(device.attach().unwrap_err().kind(), ErrorKind::Busy);
+        assert!(!input.is_attached("a", EVDEVS[0]));
+
+        input.heal();
+        device.attach().unwrap();
+    }
+
+    #[test]
+    fn switch_rolls_back() {
+        let input = MockInput::new().domain("a");
+        input.fail(MockOp::Attach, EVDEVS[2], ErrorKind::Busy);
+
+        match group(&input, "a").switch(Target::Attached) {
+            Err(e @ Error::GroupSwitch { .. }) => {
+                assert_eq!(e.kind(), ErrorKind::Busy);
+                assert!(e.stranded().is_empty());
+            },
+            r => panic!("expected a group switch error, got {:?}", r),
+        }
+        for e in &EVDEVS {
+            assert!(!input.is_attached("a", e));
+        }
+    }
+
+    #[test]
+    fn switch_strands() {
+        let input = MockInput::new().domain("a");
+        input.fail(MockOp::Attach, EVDEVS[1], ErrorKind::Busy);
+        input.fail(MockOp::Detach, EVDEVS[0], ErrorKind::Monitor);
+
+        match group(&input, "a").switch(Target::Attached) {
+            Err(e) => assert_eq!(e.stranded(), &[EVDEVS[0].to_owned()]),
+            r => panic!("expected a group switch error, got {:?}", r),
+        }
+        assert!(input.is_attached("a", EVDEVS[0]));
+        assert!(!input.is_attached("a", EVDEVS[1]));
+    }
+
+    #[test]
+    fn nested_groups() {
+        // more nested groups than pool threads, each waiting on its own devices
+        let input = MockInput::new().domain("a").attached("a", EVDEVS[0]);
+        let groups = (0..64)
+            .map(|_| Arc::new(group(&input, "a")) as Arc<Device>)
+            .collect();
+        let states = DeviceGroup::new("a", groups).states().unwrap();
+        assert_eq!(states.len(), 64);
+        assert!(states.iter().all(|a| *a));
+    }
+
+    #[test]
+    fn moves() {
+        let input = MockInput::new().domain("a").domain("b").attached("a", EVDEVS[0]).attached("a", EVDEVS[1]);
+        move_group(&group(&input, "a"), &group(&input, "b")).unwrap();
+        for e in &EVDEVS {
+            assert!(!input.is_attached("a", e));
+            assert!(input.is_attached("b", e));
+        }
+    }
+
+    #[test]
+    fn move_rolls_back() {
+        let input = MockInput::new().domain("a").domain("b").attached("a", EVDEVS[0]);
+        input.fail(MockOp::Attach, EVDEVS[1], ErrorKind::Busy);
+
+        match move_group(&group(&input, "a"), &group(&input, "b")) {
+            Err(e) => {
+                assert_eq!(e.restored(), Some(true));
+                assert_eq!(e.kind(), ErrorKind::Busy);
+            },
+            r => panic!("expected a move error, got {:?}", r),
+        }
+        // only what `a` had before, nothing left on `b`
+        assert!(input.is_attached("a", EVDEVS[0]));
+        assert!(!input.is_attached("a", EVDEVS[2]));
+        for e in &EVDEVS {
+            assert!(!input.is_attached("b", e));
+        }
+    }
+}
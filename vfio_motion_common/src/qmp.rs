@@ -0,0 +1,642 @@
+//! Talking QMP to QEMU directly over its unix socket, for domains that aren't managed by libvirt. Without the
+//! `qmp` feature only `Error` and `QmpError` are built, `libvirt` and `remote` report monitor errors with them too.
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use std::io::{BufRead, BufReader, Write};
+use std::io;
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use std::path::{Path, PathBuf};
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use std::time::{Duration, Instant};
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use std::collections::{HashMap, VecDeque};
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use std::os::unix::net::UnixStream;
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use std::sync::Arc;
+
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use ::serde::de::DeserializeOwned;
+use ::serde_json::{self, Value};
+
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use ::input::{self, Input, Domains, Device, DeviceGroup, Group, Status, DeviceKind, DeviceRequest, GroupRequest,
+              Hotplug, Persistence, QmpBus, qmp_device_id, qmp_device_add, qmp_device_del, qmp_free_slot, qmp_plugged};
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+use ::evdev::{self, EvdevInfo, EvdevPolicy};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error) {
+            from()
+            display("QMP socket I/O error: {}", err)
+        }
+        Serde(err: serde_json::Error) {
+            from()
+            display("invalid QMP message: {}", err)
+        }
+        Unexpected(msg: Value) {
+            display("unexpected QMP message: {}", msg)
+        }
+        Command(err: QmpError) {
+            display("QEMU monitor error ({}): {}", err.class, err.desc)
+        }
+        Closed {
+            description("QMP socket closed")
+        }
+        Timeout(event: String) {
+            display("timed out waiting for QMP event {}", event)
+        }
+        NoSocket(domain: String) {
+            display("no QMP socket configured for domain '{}'", domain)
+        }
+    }
+}
+
+/// An error reply from QEMU's monitor
+#[derive(Clone, Debug, Deserialize)]
+pub struct QmpError {
+    pub class: String,
+    pub desc: String,
+}
+
+/// How long to wait on the socket outside of `wait_event()`, QEMU answers straight away unless it's hung
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the guest gets to give up a device after `device_del`
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+pub const DEVICE_DELETED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A QMP connection that's finished capability negotiation. Events that arrive while waiting for a reply are
+/// kept for `wait_event()`.
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    events: VecDeque<Value>,
+}
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+impl Qmp {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Qmp, Error> {
+        Qmp::from_stream(UnixStream::connect(path)?)
+    }
+    /// Negotiate on an already connected stream, e.g. one end of `UnixStream::pair()`
+    pub fn from_stream(stream: UnixStream) -> Result<Qmp, Error> {
+        // a socket nobody's serving (e.g. QEMU is stopped in a debugger) would otherwise block forever
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut qmp = Qmp {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            events: VecDeque::new(),
+        };
+
+        let greeting = qmp.read()?;
+        if greeting.get("QMP").is_none() {
+            return Err(Error::Unexpected(greeting));
+        }
+        debug!("connected to QEMU {}", greeting["QMP"]["version"]["qemu"]);
+        qmp.execute::<Value>(r#"{"execute":"qmp_capabilities"}"#)?;
+        Ok(qmp)
+    }
+
+    fn read(&mut self) -> Result<Value, Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::Closed);
+        }
+        trace!("qmp <- {}", line.trim_right());
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Run a command, deserializing what it returns
+    pub fn execute<T: DeserializeOwned>(&mut self, command: &str) -> Result<T, Error> {
+        // QMP is one message per line
+        let command = serde_json::to_string(&serde_json::from_str::<Value>(command)?)?;
+        trace!("qmp -> {}", command);
+        self.writer.write_all(command.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+
+        loop {
+            let mut msg = self.read()?;
+            if msg.get("event").is_some() {
+                self.events.push_back(msg);
+                continue;
+            }
+
+            if let Some(err) = msg.get_mut("error").map(|e| e.take()) {
+                return Err(Error::Command(serde_json::from_value(err)?));
+            }
+            return match msg.get_mut("return").map(|r| r.take()) {
+                Some(ret) => Ok(serde_json::from_value(ret)?),
+                None => Err(Error::Unexpected(msg))
+            };
+        }
+    }
+    /// Wait up to `timeout` for `event` with data that `matches`, returning the data
+    pub fn wait_event<F: Fn(&Value) -> bool>(&mut self, event: &str, matches: F, timeout: Duration) -> Result<Value, Error> {
+        if let Some(i) = self.events.iter().position(|e| e["event"] == event && matches(&e["data"])) {
+            return Ok(self.events.remove(i).unwrap()["data"].take());
+        }
+
+        let deadline = Instant::now() + timeout;
+        let res = loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(Error::Timeout(event.to_owned()));
+            }
+            self.reader.get_ref().set_read_timeout(Some(deadline - now))?;
+
+            let mut msg = match self.read() {
+                Ok(m) => m,
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                    break Err(Error::Timeout(event.to_owned())),
+                Err(e) => break Err(e)
+            };
+            if msg["event"] == event && matches(&msg["data"]) {
+                break Ok(msg["data"].take());
+            }
+            if msg.get("event").is_some() {
+                self.events.push_back(msg);
+            }
+        };
+
+        self.reader.get_ref().set_read_timeout(Some(IO_TIMEOUT))?;
+        res
+    }
+}
+
+/// `Input` for QEMU instances that aren't managed by libvirt, using the QMP socket configured for each domain
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+#[derive(Clone)]
+pub struct QmpInput {
+    sockets: Arc<HashMap<String, PathBuf>>,
+    policy: Arc<EvdevPolicy>,
+    bus: QmpBus,
+}
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+impl QmpInput {
+    /// `sockets` is keyed by domain name
+    pub fn new(sockets: HashMap<String, PathBuf>) -> QmpInput {
+        QmpInput {
+            sockets: Arc::new(sockets),
+            policy: Arc::new(EvdevPolicy::default()),
+            bus: QmpBus::default(),
+        }
+    }
+    /// Restrict which evdevs can be passed through, defaults to anything under `/dev/input`
+    pub fn evdev_policy(mut self, policy: EvdevPolicy) -> QmpInput {
+        self.policy = Arc::new(policy);
+        self
+    }
+    pub fn bus(mut self, bus: QmpBus) -> QmpInput {
+        self.bus = bus;
+        self
+    }
+
+    fn socket(&self, domain: &str) -> Result<&PathBuf, Error> {
+        self.sockets.get(domain).ok_or_else(|| Error::NoSocket(domain.to_owned()))
+    }
+
+    /// Only evdevs hotplugged into the running domain with `device_add` are possible, anything else a request
+    /// asks for is refused rather than quietly ignored
+    fn check(evdevs: &[String], kind: Option<DeviceKind>, hotplug: Option<Hotplug>, persistence: Option<Persistence>)
+             -> Result<(), input::Error> {
+        if let Some(spec) = evdevs.iter().find(|e| DeviceKind::split(e, kind).0 == DeviceKind::Usb) {
+            return Err(input::Error::BadRequest(format!("{:?} is a USB device, the QMP backend only passes evdevs through",
+                                                        spec)));
+        }
+        match hotplug {
+            Some(Hotplug::Qmp) | None => {},
+            Some(h) => return Err(input::Error::BadRequest(format!("the QMP backend can't hotplug with {:?}", h)))
+        }
+        match persistence {
+            Some(Persistence::Live) | None => Ok(()),
+            Some(_) => Err(input::Error::BadRequest("domains without libvirt have no persistent definition".to_owned()))
+        }
+    }
+}
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+impl Input for QmpInput {
+    fn domains(&self) -> Arc<Domains> {
+        Arc::new(QmpDomains(self.sockets.clone()))
+    }
+    fn device(&self, domain: &str, evdev: &str) -> Result<Arc<Device>, input::Error> {
+        let socket = self.socket(domain)?;
+        let mut devices = Vec::new();
+        for node in evdev::resolve(evdev)? {
+            devices.push(Arc::new(QmpDevice::new(domain, socket, node, &self.policy, &self.bus)?) as Arc<Device>);
+        }
+
+        if devices.len() == 1 {
+            return Ok(devices.remove(0));
+        }
+        Ok(Arc::new(DeviceGroup::new(domain, devices).name(evdev)))
+    }
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, input::Error> {
+        Ok(evdev::list()?)
+    }
+    fn request(&self, req: &DeviceRequest, _hotplug: Hotplug) -> Result<Arc<Device>, input::Error> {
+        QmpInput::check(::std::slice::from_ref(&req.evdev), req.kind, req.hotplug, req.persistence)?;
+        self.device(&req.domain, &req.evdev)
+    }
+    fn request_group(&self, req: &GroupRequest, _hotplug: Hotplug) -> Result<Arc<Group>, input::Error> {
+        QmpInput::check(&req.evdevs, req.kind, req.hotplug, req.persistence)?;
+        self.group(&req.domain, &req.evdevs)
+    }
+}
+
+/// Domains whose QMP socket exists, i.e. that are probably running
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+#[derive(Clone)]
+pub struct QmpDomains(Arc<HashMap<String, PathBuf>>);
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+impl Domains for QmpDomains {
+    fn list(&self) -> Result<Vec<String>, input::Error> {
+        let mut domains = self.0.iter()
+            .filter(|&(_, socket)| socket.exists())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        domains.sort();
+        Ok(domains)
+    }
+}
+
+/// An evdev hotplugged with `device_add`, connecting to the QMP socket for each operation so other clients can
+/// use it in between
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+#[derive(Clone)]
+pub struct QmpDevice {
+    evdev: String,
+    /// What `evdev` resolved to when it was checked
+    path: String,
+    domain: String,
+    socket: PathBuf,
+    id: String,
+    bus: QmpBus,
+}
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+impl QmpDevice {
+    pub fn new(domain: &str, socket: &Path, evdev: String, policy: &EvdevPolicy, bus: &QmpBus) -> Result<QmpDevice, input::Error> {
+        let path = policy.check(&evdev)?.to_string_lossy().into_owned();
+        Ok(QmpDevice::checked(domain, socket, evdev, path, bus))
+    }
+    /// With `path`, what `evdev` resolves to, already checked against the policy
+    pub(crate) fn checked(domain: &str, socket: &Path, evdev: String, path: String, bus: &QmpBus) -> QmpDevice {
+        let id = qmp_device_id(&evdev);
+        QmpDevice {
+            evdev,
+            path,
+            domain: domain.to_owned(),
+            socket: socket.to_owned(),
+            id,
+            bus: bus.clone(),
+        }
+    }
+
+    fn query_pci(&self, qmp: &mut Qmp) -> Result<Value, Error> {
+        qmp.execute(r#"{"execute":"query-pci"}"#)
+    }
+}
+#[cfg(all(feature = "qmp", target_os = "linux"))]
+impl Device for QmpDevice {
+    fn evdev(&self) -> &str {
+        &self.evdev
+    }
+    fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn status(&self) -> Status {
+        match Qmp::connect(&self.socket).and_then(|mut q| self.query_pci(&mut q)) {
+            Ok(pci) => qmp_plugged(&pci, &self.id).into(),
+            Err(e) => Status::Unknown(e.into())
+        }
+    }
+
+    fn attach(&self) -> Result<(), input::Error> {
+        let mut qmp = Qmp::connect(&self.socket)?;
+        let pci = self.query_pci(&mut qmp)?;
+        if qmp_plugged(&pci, &self.id) {
+            return Err(input::Error::AlreadyAttached(self.evdev.clone()));
+        }
+
+        let addr = qmp_free_slot(&pci, &self.bus).ok_or(input::Error::BadState("No free PCI slots for hotplug!"))?;
+        debug!("hotplugging '{}' as '{}' at {} slot {:#x}", self.evdev, self.id, self.bus.id, addr);
+        qmp.execute::<Value>(&qmp_device_add(&self.id, &self.path, &self.bus, addr))?;
+        Ok(())
+    }
+    fn detach(&self) -> Result<(), input::Error> {
+        let mut qmp = Qmp::connect(&self.socket)?;
+        if !qmp_plugged(&self.query_pci(&mut qmp)?, &self.id) {
+            return Err(input::Error::NotAttached(self.evdev.clone()));
+        }
+
+        qmp.execute::<Value>(&qmp_device_del(&self.id))?;
+        // `device_del` only asks the guest to give the device up
+        qmp.wait_event("DEVICE_DELETED", |d| d["device"] == self.id.as_str(), DEVICE_DELETED_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "qmp", target_os = "linux"))]
+mod tests {
+    use std::{env, fs, process};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::collections::HashMap;
+
+    use ::serde_json::{self, Value};
+
+    use super::*;
+
+    const EVDEV: &'static str = "/dev/input/event3";
+
+    /// The QEMU end of the socket
+    struct Fake {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+    }
+    impl Fake {
+        fn send(&mut self, msg: Value) {
+            writeln!(self.writer, "{}", msg).unwrap();
+        }
+        fn recv(&mut self) -> Value {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    /// Greet and negotiate on the QEMU end of a connection
+    fn accept(stream: UnixStream) -> Fake {
+        let mut fake = Fake {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        fake.send(json!({ "QMP": { "version": { "qemu": { "major": 3, "minor": 0, "micro": 0 } }, "capabilities": [] } }));
+        assert_eq!(fake.recv()["execute"], "qmp_capabilities");
+        fake.send(json!({ "return": {} }));
+        fake
+    }
+
+    /// Greet and negotiate, then hand the socket to `script`
+    fn connect<F: FnOnce(Fake) + Send + 'static>(script: F) -> (Qmp, thread::JoinHandle<()>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || script(accept(server)));
+        (Qmp::from_stream(client).unwrap(), handle)
+    }
+    /// Serve one connection on a socket at a fresh path with `script`, for devices that connect by themselves
+    fn listen<F: FnOnce(Fake) + Send + 'static>(name: &str, script: F) -> (PathBuf, thread::JoinHandle<()>) {
+        let path = env::temp_dir().join(format!("vfio-motion-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || script(accept(listener.accept().unwrap().0)));
+        (path, handle)
+    }
+
+    /// A `query-pci` reply with a device in each of `slots` on bus 0
+    fn pci(slots: &[(u64, &str)]) -> Value {
+        let devices: Vec<_> = slots.iter()
+            .map(|&(slot, id)| json!({ "bus": 0, "slot": slot, "function": 0, "qdev_id": id }))
+            .collect();
+        json!([{ "bus": 0, "devices": devices }])
+    }
+    fn device(path: &Path) -> QmpDevice {
+        QmpDevice::checked("win10", path, EVDEV.to_owned(), EVDEV.to_owned(), &QmpBus::default())
+    }
+
+    fn deleted(device: &str) -> Value {
+        json!({ "event": "DEVICE_DELETED", "data": { "device": device, "path": "/machine/peripheral/x" } })
+    }
+
+    #[test]
+    fn negotiates() {
+        let (_qmp, server) = connect(|_| {});
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn error_reply() {
+        let (mut qmp, server) = connect(|mut fake| {
+            assert_eq!(fake.recv()["execute"], "device_del");
+            fake.send(json!({ "error": { "class": "DeviceNotFound", "desc": "Device 'x' not found" } }));
+        });
+
+        match qmp.execute::<Value>(r#"{"execute":"device_del","arguments":{"id":"x"}}"#) {
+            Err(Error::Command(e)) => assert_eq!(e.class, "DeviceNotFound"),
+            r => panic!("expected a command error, got {:?}", r)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn events_before_return() {
+        let (mut qmp, server) = connect(|mut fake| {
+            fake.recv();
+            fake.send(deleted("a"));
+            fake.send(json!({ "event": "RESET", "data": {} }));
+            fake.send(json!({ "return": { "running": true } }));
+        });
+
+        let status: Value = qmp.execute(r#"{"execute":"query-status"}"#).unwrap();
+        assert_eq!(status["running"], true);
+        // kept for later
+        let data = qmp.wait_event("DEVICE_DELETED", |d| d["device"] == "a", Duration::from_millis(0)).unwrap();
+        assert_eq!(data["device"], "a");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn wait_event_times_out() {
+        let (done, finished) = mpsc::channel::<()>();
+        let (mut qmp, server) = connect(move |_fake| {
+            // hold the socket open until the client's given up
+            let _ = finished.recv();
+        });
+
+        match qmp.wait_event("DEVICE_DELETED", |_| true, Duration::from_millis(100)) {
+            Err(Error::Timeout(ref event)) if event == "DEVICE_DELETED" => {},
+            r => panic!("expected a timeout, got {:?}", r)
+        }
+        done.send(()).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn wait_event_matches() {
+        let (mut qmp, server) = connect(|mut fake| {
+            fake.send(deleted("other"));
+            fake.send(deleted("vfio-motion.dev.input.event3"));
+        });
+
+        let data = qmp.wait_event("DEVICE_DELETED", |d| d["device"] == "vfio-motion.dev.input.event3",
+                                  Duration::from_secs(5)).unwrap();
+        assert_eq!(data["device"], "vfio-motion.dev.input.event3");
+        // the other one is still there
+        assert!(qmp.wait_event("DEVICE_DELETED", |d| d["device"] == "other", Duration::from_millis(0)).is_ok());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn device_attach() {
+        let (added, adds) = mpsc::channel();
+        let (path, server) = listen("attach", move |mut fake| {
+            assert_eq!(fake.recv()["execute"], "query-pci");
+            fake.send(json!({ "return": pci(&[(0x1, ""), (0x10, "net0"), (0x11, "")]) }));
+            added.send(fake.recv()).unwrap();
+            fake.send(json!({ "return": {} }));
+        });
+
+        device(&path).attach().unwrap();
+        server.join().unwrap();
+        let _ = fs::remove_file(&path);
+        // the first slot in the bus's range nothing's using
+        let add = adds.recv().unwrap();
+        assert_eq!(add["execute"], "device_add");
+        assert_eq!(add["arguments"]["id"], json!(qmp_device_id(EVDEV)));
+        assert_eq!(add["arguments"]["bus"], "pci.0");
+        assert_eq!(add["arguments"]["addr"], "0x12");
+    }
+
+    #[test]
+    fn device_detach() {
+        let id = qmp_device_id(EVDEV);
+        let (path, server) = listen("detach", move |mut fake| {
+            assert_eq!(fake.recv()["execute"], "query-pci");
+            fake.send(json!({ "return": pci(&[(0x10, id.as_str())]) }));
+            let del = fake.recv();
+            assert_eq!(del["execute"], "device_del");
+            assert_eq!(del["arguments"]["id"], json!(id));
+            fake.send(json!({ "return": {} }));
+
+            // the guest takes its time
+            thread::sleep(Duration::from_millis(200));
+            fake.send(deleted("other"));
+            fake.send(deleted(&id));
+        });
+
+        let start = Instant::now();
+        device(&path).detach().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        server.join().unwrap();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn refuses_what_it_cant_do() {
+        use ::input::{Input, ErrorKind, DeviceRequest, Hotplug, Persistence};
+
+        let input = QmpInput::new(HashMap::new());
+        let request = |evdev: &str, hotplug, persistence| DeviceRequest {
+            domain: "win10".to_owned(),
+            evdev: evdev.to_owned(),
+            kind: None,
+            hotplug,
+            persistence,
+        };
+        for req in &[request("usb:046d:c52b", None, None), request(EVDEV, Some(Hotplug::Libvirt), None),
+                     request(EVDEV, None, Some(Persistence::Config))] {
+            match input.request(req, Hotplug::Libvirt) {
+                Err(e) => assert_eq!(e.kind(), ErrorKind::BadRequest),
+                Ok(_) => panic!("expected {:?} to be refused", req)
+            }
+        }
+    }
+
+    /// What QEMU lists for an input-linux object, there's no property showing where the grab is
+    const INPUT_LINUX: &'static [&'static str] = &["type", "evdev", "grab_all", "repeat", "grab-toggle"];
+
+    /// Answers qom commands like QEMU would for an input-linux object until the client hangs up, sending anything
+    /// that asked for a property it doesn't have to `rejected`
+    fn input_linux(mut fake: Fake, rejected: mpsc::Sender<Value>) {
+        loop {
+            let mut line = String::new();
+            if fake.reader.read_line(&mut line).unwrap() == 0 {
+                return;
+            }
+            let command: Value = serde_json::from_str(&line).unwrap();
+            let execute = command["execute"].as_str().unwrap().to_owned();
+            let property = command["arguments"]["property"].as_str().map(|p| p.to_owned());
+            match (execute.as_str(), property) {
+                ("qom-list", _) => {
+                    let props: Vec<_> = INPUT_LINUX.iter().map(|p| json!({ "name": p, "type": "str" })).collect();
+                    fake.send(json!({ "return": props }));
+                },
+                (_, Some(ref p)) if INPUT_LINUX.contains(&p.as_str()) => fake.send(json!({ "return": "" })),
+                (_, p) => {
+                    fake.send(json!({ "error": {
+                        "class": "GenericError",
+                        "desc": format!("Property 'input-linux.{}' not found", p.unwrap_or_default())
+                    } }));
+                    rejected.send(command).unwrap();
+                }
+            }
+        }
+    }
+
+    /// A running domain that keeps whatever was last attached, with its monitor on the fake socket
+    struct FakeDomain {
+        devices: ::std::sync::Mutex<String>,
+        qmp: ::std::sync::Mutex<Qmp>,
+    }
+    impl ::input::DomainOps for FakeDomain {
+        fn name(&self) -> Result<String, ::input::Error> {
+            Ok("win10".to_owned())
+        }
+        fn xml(&self, _flags: u32) -> Result<String, ::input::Error> {
+            Ok(format!("<domain><name>win10</name><devices>{}</devices></domain>", self.devices.lock().unwrap()))
+        }
+        fn attach_device(&self, xml: &str, _flags: u32) -> Result<(), ::input::Error> {
+            *self.devices.lock().unwrap() = xml.to_owned();
+            Ok(())
+        }
+        fn detach_device_alias(&self, _alias: &str, _flags: u32) -> Result<(), ::input::Error> {
+            self.devices.lock().unwrap().clear();
+            Ok(())
+        }
+        fn qmp(&self, command: &str) -> Result<Value, ::input::Error> {
+            Ok(self.qmp.lock().unwrap().execute(command)?)
+        }
+    }
+
+    #[test]
+    fn evdev_grab() {
+        use ::input::{Device, Error as InputError, NativeEvdevDevice, Persistence, Status};
+
+        let (rejected, rejections) = mpsc::channel();
+        let (qmp, server) = connect(move |fake| input_linux(fake, rejected));
+        let domain = ::std::sync::Arc::new(FakeDomain { devices: Default::default(), qmp: ::std::sync::Mutex::new(qmp) });
+        let device = NativeEvdevDevice::checked(domain.clone(), "/dev/input/event3".to_owned(),
+                                                "/dev/input/event3".to_owned(), Default::default()).unwrap()
+            .persistence(Persistence::Live);
+
+        assert!(match device.status() { Status::Detached => true, _ => false });
+        device.attach().unwrap();
+        assert!(domain.devices.lock().unwrap().contains("grabToggle="));
+        match device.status() {
+            Status::Unknown(InputError::Unsupported(_)) => {},
+            s => panic!("expected the grab to be unknown, got {:?}", s)
+        }
+        match device.attach() {
+            Err(InputError::AlreadyAttached(_)) => {},
+            r => panic!("expected the input to be set up already, got {:?}", r)
+        }
+
+        device.detach().unwrap();
+        assert!(match device.status() { Status::Detached => true, _ => false });
+        match device.detach() {
+            Err(InputError::NotAttached(_)) => {},
+            r => panic!("expected the input to be gone, got {:?}", r)
+        }
+
+        drop(device);
+        drop(domain);
+        server.join().unwrap();
+        assert!(rejections.try_recv().is_err());
+    }
+}
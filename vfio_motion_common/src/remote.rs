@@ -0,0 +1,659 @@
+//! A pure-Rust client for the few calls of libvirt's remote protocol that the native backend needs, so it
+//! can talk to `qemu+tcp://` without linking libvirt.
+//!
+//! Procedure numbers and message layouts are from libvirt's `remote_protocol.x` and `qemu_protocol.x`. Only
+//! servers that don't require authentication (`auth_tcp = "none"`) are supported. Nothing here links libvirt,
+//! the constants shared with `libvirt` are plain values.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ::qmp::{self, QmpError};
+use ::input::{self, Input, Domains, Device, Group, DomainOps, DeviceGroup, DeviceRequest, GroupRequest, ErrorKind,
+              Health, Hotplug, Grab, NativeDevices, Persistence, QmpBus, Templates};
+use ::evdev::{EvdevInfo, EvdevPolicy};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error) {
+            from()
+            display("libvirt remote I/O error: {}", err)
+        }
+        Uri(uri: String) {
+            display("unsupported libvirt URI '{}', only <driver>+tcp:// is supported", uri)
+        }
+        Protocol(msg: &'static str) {
+            display("libvirt remote protocol error: {}", msg)
+        }
+        Libvirt { code: i32, domain: i32, message: String } {
+            display("{}", message)
+        }
+    }
+}
+impl From<Error> for input::Error {
+    fn from(err: Error) -> input::Error {
+        let kind = match err {
+            Error::Libvirt { code, domain, .. } => ErrorKind::from_code(code, domain),
+            Error::Io(_) => ErrorKind::ConnectionLost,
+            Error::Uri(_) => ErrorKind::BadRequest,
+            Error::Protocol(_) => ErrorKind::Other,
+        };
+        input::Error::Remote(kind, format!("{}", err), Vec::new())
+    }
+}
+
+pub const DEFAULT_PORT: u16 = 16509;
+/// How long connecting or a call waits on libvirtd before the connection is given up on
+pub const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long closing a connection waits on libvirtd, nobody's waiting on the answer
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const REMOTE_PROGRAM: u32 = 0x20008086;
+const QEMU_PROGRAM: u32 = 0x20008087;
+const PROTOCOL_VERSION: u32 = 1;
+
+// remote_procedure
+const REMOTE_PROC_CONNECT_OPEN: i32 = 1;
+const REMOTE_PROC_CONNECT_CLOSE: i32 = 2;
+const REMOTE_PROC_DOMAIN_GET_XML_DESC: i32 = 14;
+const REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME: i32 = 23;
+const REMOTE_PROC_DOMAIN_ATTACH_DEVICE_FLAGS: i32 = 160;
+const REMOTE_PROC_CONNECT_LIST_ALL_DOMAINS: i32 = 273;
+const REMOTE_PROC_DOMAIN_DETACH_DEVICE_ALIAS: i32 = 393;
+// qemu_procedure
+const QEMU_PROC_DOMAIN_MONITOR_COMMAND: i32 = 1;
+
+// virNetMessageType and virNetMessageStatus
+const VIR_NET_CALL: i32 = 0;
+const VIR_NET_REPLY: i32 = 1;
+const VIR_NET_OK: i32 = 0;
+const VIR_NET_ERROR: i32 = 1;
+
+const VIR_CONNECT_LIST_DOMAINS_ACTIVE: u32 = 1;
+const VIR_DOMAIN_QEMU_MONITOR_COMMAND_DEFAULT: u32 = 0;
+/// Largest message libvirt will send (`VIR_NET_MESSAGE_MAX`)
+const MESSAGE_MAX: usize = 32 * 1024 * 1024;
+
+/// XDR encoding of call arguments
+#[derive(Default)]
+struct XdrWriter(Vec<u8>);
+impl XdrWriter {
+    fn int(mut self, v: i32) -> Self {
+        self.0.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+        self
+    }
+    fn uint(self, v: u32) -> Self {
+        self.int(v as i32)
+    }
+    fn opaque(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        let pad = (4 - bytes.len() % 4) % 4;
+        self.0.extend_from_slice(&[0; 3][..pad]);
+        self
+    }
+    fn string(self, s: &str) -> Self {
+        self.uint(s.len() as u32).opaque(s.as_bytes())
+    }
+    fn optional_string(self, s: Option<&str>) -> Self {
+        match s {
+            Some(s) => self.uint(1).string(s),
+            None => self.uint(0),
+        }
+    }
+    fn domain(self, dom: &RemoteDomainId) -> Self {
+        self.string(&dom.name).opaque(&dom.uuid).int(dom.id)
+    }
+}
+
+/// XDR decoding of replies
+struct XdrReader<'a>(&'a [u8]);
+impl<'a> XdrReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let padded = len + (4 - len % 4) % 4;
+        if self.0.len() < padded {
+            return Err(Error::Protocol("truncated message"));
+        }
+        let (bytes, rest) = self.0.split_at(padded);
+        self.0 = rest;
+        Ok(&bytes[..len])
+    }
+    fn int(&mut self) -> Result<i32, Error> {
+        let b = self.bytes(4)?;
+        Ok((b[0] as i32) << 24 | (b[1] as i32) << 16 | (b[2] as i32) << 8 | b[3] as i32)
+    }
+    fn uint(&mut self) -> Result<u32, Error> {
+        Ok(self.int()? as u32)
+    }
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.uint()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+    fn optional_string(&mut self) -> Result<Option<String>, Error> {
+        match self.uint()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.string()?)),
+        }
+    }
+    fn domain(&mut self) -> Result<RemoteDomainId, Error> {
+        let name = self.string()?;
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(self.bytes(16)?);
+        Ok(RemoteDomainId {
+            name,
+            uuid,
+            id: self.int()?,
+        })
+    }
+}
+
+/// `remote_nonnull_domain`
+#[derive(Clone, Debug)]
+struct RemoteDomainId {
+    name: String,
+    uuid: [u8; 16],
+    id: i32,
+}
+
+/// The header and `args` of a call, framed with its length
+fn packet(program: u32, procedure: i32, serial: u32, args: &XdrWriter) -> Vec<u8> {
+    let header = XdrWriter::default()
+        .uint(program)
+        .uint(PROTOCOL_VERSION)
+        .int(procedure)
+        .int(VIR_NET_CALL)
+        .uint(serial)
+        .int(VIR_NET_OK);
+    let len = 4 + header.0.len() + args.0.len();
+    let mut packet = XdrWriter::default().uint(len as u32).0;
+    packet.extend_from_slice(&header.0);
+    packet.extend_from_slice(&args.0);
+    packet
+}
+/// The body of the reply to call `serial` from message `msg` (without its length), `None` if `msg` is something
+/// else (e.g. an event)
+fn reply(msg: &[u8], program: u32, procedure: i32, serial: u32) -> Result<Option<Vec<u8>>, Error> {
+    let mut r = XdrReader(msg);
+    let (prog, _vers, procedure_, kind, reply_serial, status) =
+        (r.uint()?, r.uint()?, r.int()?, r.int()?, r.uint()?, r.int()?);
+    if prog != program || procedure_ != procedure || kind != VIR_NET_REPLY || reply_serial != serial {
+        return Ok(None);
+    }
+
+    match status {
+        VIR_NET_OK => Ok(Some(r.0.to_vec())),
+        VIR_NET_ERROR => Err(Error::Libvirt {
+            code: r.int()?,
+            domain: r.int()?,
+            message: r.optional_string()?.unwrap_or_else(|| "unknown libvirt error".to_owned()),
+        }),
+        _ => Err(Error::Protocol("unexpected reply status")),
+    }
+}
+
+struct Stream {
+    stream: TcpStream,
+    serial: u32,
+}
+impl Stream {
+    /// Connect to `address` and open driver URI `name` on it
+    fn open(address: &str, name: &str) -> Result<Stream, Error> {
+        let addr = address.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to"))?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let mut s = Stream {
+            stream,
+            serial: 0,
+        };
+        s.call(REMOTE_PROGRAM, REMOTE_PROC_CONNECT_OPEN, &XdrWriter::default().optional_string(Some(name)).uint(0))?;
+        Ok(s)
+    }
+
+    /// Tell libvirtd we're going, without holding up whoever dropped the connection for long
+    fn close(&mut self) -> Result<(), Error> {
+        self.stream.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        self.stream.set_write_timeout(Some(CLOSE_TIMEOUT))?;
+        self.call(REMOTE_PROGRAM, REMOTE_PROC_CONNECT_CLOSE, &XdrWriter::default())?;
+        Ok(())
+    }
+
+    fn call(&mut self, program: u32, procedure: i32, args: &XdrWriter) -> Result<Vec<u8>, Error> {
+        self.serial = self.serial.wrapping_add(1);
+        let serial = self.serial;
+        trace!("libvirt remote call {}:{} serial {}", program, procedure, serial);
+        self.stream.write_all(&packet(program, procedure, serial, args))?;
+
+        loop {
+            let mut len = [0; 4];
+            self.stream.read_exact(&mut len)?;
+            let len = XdrReader(&len[..]).uint()? as usize;
+            if len < 28 || len > MESSAGE_MAX {
+                return Err(Error::Protocol("bad message length"));
+            }
+            let mut msg = vec![0; len - 4];
+            self.stream.read_exact(&mut msg)?;
+
+            // events and the like, which we never ask for
+            if let Some(ret) = reply(&msg, program, procedure, serial)? {
+                return Ok(ret);
+            }
+        }
+    }
+}
+
+/// A connection to libvirtd over its TCP socket, calls are made one at a time. A connection that dies (or stops
+/// answering within `IO_TIMEOUT`) is dropped and reopened by the next call.
+pub struct RemoteConnection {
+    uri: String,
+    address: String,
+    name: String,
+    stream: Mutex<Option<Stream>>,
+    health: Mutex<Health>,
+}
+impl RemoteConnection {
+    /// Open `<driver>+tcp://host[:port]/path`
+    pub fn open(uri: &str) -> Result<RemoteConnection, Error> {
+        let (driver, rest) = match uri.find("+tcp://") {
+            Some(i) if i > 0 => (&uri[..i], &uri[i + 7..]),
+            _ => return Err(Error::Uri(uri.to_owned()))
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/system"),
+        };
+        let address = match authority.find(':') {
+            Some(_) => authority.to_owned(),
+            None => format!("{}:{}", authority, DEFAULT_PORT),
+        };
+        // the daemon wants the URI as the driver on its side would see it
+        let name = format!("{}://{}", driver, path);
+
+        let stream = Stream::open(&address, &name)?;
+        debug!("opened libvirt remote connection to '{}' at {}", name, address);
+        Ok(RemoteConnection {
+            uri: uri.to_owned(),
+            address,
+            name,
+            stream: Mutex::new(Some(stream)),
+            health: Mutex::new(Health { connected: true, ..Default::default() }),
+        })
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+    pub fn health(&self) -> Health {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Make a call, reconnecting first if the last one lost the connection. Calls aren't retried, one that
+    /// fails with the connection might still have happened.
+    fn call(&self, program: u32, procedure: i32, args: XdrWriter) -> Result<Vec<u8>, Error> {
+        let mut stream = self.stream.lock().unwrap();
+        if stream.is_none() {
+            *stream = Some(self.reconnect()?);
+        }
+
+        let ret = stream.as_mut().unwrap().call(program, procedure, &args);
+        match ret {
+            Err(Error::Io(_)) | Err(Error::Protocol(_)) => {
+                *stream = None;
+                let mut health = self.health.lock().unwrap();
+                if health.connected {
+                    warn!("lost libvirt remote connection to '{}', reconnecting on the next call", self.uri);
+                    health.connected = false;
+                    health.attempts = 0;
+                }
+            },
+            _ => {}
+        }
+        ret
+    }
+    fn reconnect(&self) -> Result<Stream, Error> {
+        match Stream::open(&self.address, &self.name) {
+            Ok(s) => {
+                let mut health = self.health.lock().unwrap();
+                info!("reconnected to libvirt on '{}' after {} failed attempts", self.uri, health.attempts);
+                health.connected = true;
+                health.reconnects += 1;
+                health.attempts = 0;
+                health.last_error = None;
+                Ok(s)
+            },
+            Err(e) => {
+                debug!("failed to reconnect to libvirt on '{}': {}", self.uri, e);
+                let mut health = self.health.lock().unwrap();
+                health.attempts += 1;
+                health.last_error = Some(format!("{}", e));
+                Err(e)
+            }
+        }
+    }
+
+    /// Names of the running domains
+    pub fn list_domains(&self) -> Result<Vec<String>, Error> {
+        let ret = self.call(REMOTE_PROGRAM, REMOTE_PROC_CONNECT_LIST_ALL_DOMAINS,
+                            XdrWriter::default().int(1).uint(VIR_CONNECT_LIST_DOMAINS_ACTIVE))?;
+        let mut r = XdrReader(&ret);
+        let count = r.uint()?;
+        let mut names = Vec::new();
+        for _ in 0..count {
+            names.push(r.domain()?.name);
+        }
+        Ok(names)
+    }
+    fn lookup(&self, name: &str) -> Result<RemoteDomainId, Error> {
+        let ret = self.call(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, XdrWriter::default().string(name))?;
+        XdrReader(&ret).domain()
+    }
+}
+impl Drop for RemoteConnection {
+    fn drop(&mut self) {
+        if let Some(ref mut s) = *self.stream.lock().unwrap() {
+            if let Err(e) = s.close() {
+                warn!("failed to close libvirt remote connection: {}", e);
+            }
+        }
+    }
+}
+
+/// A domain looked up over a `RemoteConnection`
+#[derive(Clone)]
+pub struct RemoteDomain {
+    conn: Arc<RemoteConnection>,
+    id: RemoteDomainId,
+}
+impl RemoteDomain {
+    pub fn lookup(conn: Arc<RemoteConnection>, name: &str) -> Result<RemoteDomain, Error> {
+        let id = conn.lookup(name)?;
+        Ok(RemoteDomain {
+            conn,
+            id,
+        })
+    }
+}
+impl DomainOps for RemoteDomain {
+    fn name(&self) -> Result<String, input::Error> {
+        Ok(self.id.name.clone())
+    }
+    fn xml(&self, flags: u32) -> Result<String, input::Error> {
+        let ret = self.conn.call(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_GET_XML_DESC,
+                                 XdrWriter::default().domain(&self.id).uint(flags))?;
+        Ok(XdrReader(&ret).string()?)
+    }
+    fn attach_device(&self, xml: &str, flags: u32) -> Result<(), input::Error> {
+        self.conn.call(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_ATTACH_DEVICE_FLAGS,
+                       XdrWriter::default().domain(&self.id).string(xml).uint(flags))?;
+        Ok(())
+    }
+    fn detach_device_alias(&self, alias: &str, flags: u32) -> Result<(), input::Error> {
+        self.conn.call(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_DETACH_DEVICE_ALIAS,
+                       XdrWriter::default().domain(&self.id).string(alias).uint(flags))?;
+        Ok(())
+    }
+    fn qmp(&self, command: &str) -> Result<::serde_json::Value, input::Error> {
+        let ret = self.conn.call(QEMU_PROGRAM, QEMU_PROC_DOMAIN_MONITOR_COMMAND,
+                                 XdrWriter::default().domain(&self.id).string(command).uint(VIR_DOMAIN_QEMU_MONITOR_COMMAND_DEFAULT))?;
+        let mut reply: ::serde_json::Value = ::serde_json::from_str(&XdrReader(&ret).string()?)
+            .map_err(qmp::Error::from)?;
+        if let Some(err) = reply.get_mut("error").map(|e| e.take()) {
+            let err: QmpError = ::serde_json::from_value(err).map_err(qmp::Error::from)?;
+            return Err(qmp::Error::Command(err).into());
+        }
+        Ok(reply["return"].take())
+    }
+}
+
+/// `Input` over a `RemoteConnection`, configured like `NativeInput`
+#[derive(Clone)]
+pub struct RemoteInput {
+    conn: Arc<RemoteConnection>,
+    hotplug: Hotplug,
+    devices: NativeDevices,
+}
+impl RemoteInput {
+    pub fn new(conn: RemoteConnection, hotplug: Hotplug) -> Arc<Input> {
+        Arc::new(RemoteInput::from_conn(conn, hotplug))
+    }
+    pub fn from_conn(conn: RemoteConnection, hotplug: Hotplug) -> RemoteInput {
+        RemoteInput {
+            conn: Arc::new(conn),
+            hotplug,
+            devices: NativeDevices::default(),
+        }
+    }
+    /// See `NativeDevices::evdev_policy()`
+    pub fn evdev_policy(mut self, policy: EvdevPolicy) -> RemoteInput {
+        self.devices = self.devices.evdev_policy(policy);
+        self
+    }
+    pub fn templates(mut self, templates: Templates) -> RemoteInput {
+        self.devices = self.devices.templates(templates);
+        self
+    }
+    pub fn grab(mut self, grab: Grab) -> RemoteInput {
+        self.devices = self.devices.grab(grab);
+        self
+    }
+    pub fn qmp_bus(mut self, bus: QmpBus) -> RemoteInput {
+        self.devices = self.devices.qmp_bus(bus);
+        self
+    }
+    /// See `NativeDevices::persistence()`
+    pub fn persistence(mut self, default: Persistence, devices: HashMap<String, Persistence>) -> RemoteInput {
+        self.devices = self.devices.persistence(default, devices);
+        self
+    }
+    /// See `NativeDevices::device_hotplug()`
+    pub fn device_hotplug(mut self, devices: HashMap<String, Hotplug>) -> RemoteInput {
+        self.devices = self.devices.device_hotplug(devices);
+        self
+    }
+
+    fn lookup(&self, domain: &str) -> Result<Arc<DomainOps>, input::Error> {
+        Ok(Arc::new(RemoteDomain::lookup(self.conn.clone(), domain)?))
+    }
+}
+impl Input for RemoteInput {
+    fn request(&self, req: &DeviceRequest, hotplug: Hotplug) -> Result<Arc<Device>, input::Error> {
+        let dom = self.lookup(&req.domain)?;
+        self.devices.unit(dom, &req.evdev, req.kind, req.hotplug, hotplug, req.persistence)
+    }
+    fn request_group(&self, req: &GroupRequest, hotplug: Hotplug) -> Result<Arc<Group>, input::Error> {
+        let dom = self.lookup(&req.domain)?;
+        let devices = self.devices.nodes(dom, &req.evdevs, req.kind, req.hotplug, hotplug, req.persistence)?;
+        Ok(Arc::new(DeviceGroup::new(&req.domain, devices)))
+    }
+    fn health(&self) -> Health {
+        self.conn.health()
+    }
+    fn domains(&self) -> Arc<Domains> {
+        Arc::new(RemoteDomains(self.conn.clone()))
+    }
+    fn device(&self, domain: &str, spec: &str) -> Result<Arc<Device>, input::Error> {
+        let dom = self.lookup(domain)?;
+        self.devices.unit(dom, spec, None, None, self.hotplug, None)
+    }
+    fn group(&self, domain: &str, specs: &[String]) -> Result<Arc<Group>, input::Error> {
+        let dom = self.lookup(domain)?;
+        Ok(Arc::new(DeviceGroup::new(domain, self.devices.nodes(dom, specs, None, None, self.hotplug, None)?)))
+    }
+    fn evdevs(&self) -> Result<Vec<EvdevInfo>, input::Error> {
+        Err(input::Error::Unsupported("evdev discovery"))
+    }
+}
+
+#[derive(Clone)]
+pub struct RemoteDomains(Arc<RemoteConnection>);
+impl Domains for RemoteDomains {
+    fn list(&self) -> Result<Vec<String>, input::Error> {
+        Ok(self.0.list_domains()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// A reply to call `serial` as libvirtd would frame it, less the length
+    fn reply_msg(program: u32, procedure: i32, serial: u32, status: i32, body: XdrWriter) -> Vec<u8> {
+        let mut msg = XdrWriter::default()
+            .uint(program)
+            .uint(PROTOCOL_VERSION)
+            .int(procedure)
+            .int(VIR_NET_REPLY)
+            .uint(serial)
+            .int(status)
+            .0;
+        msg.extend_from_slice(&body.0);
+        msg
+    }
+
+    #[test]
+    fn string_padding() {
+        let w = XdrWriter::default().string("abcde").string("").int(-2);
+        assert_eq!(w.0, vec![0, 0, 0, 5, b'a', b'b', b'c', b'd', b'e', 0, 0, 0,
+                             0, 0, 0, 0,
+                             0xff, 0xff, 0xff, 0xfe]);
+
+        let mut r = XdrReader(&w.0);
+        assert_eq!(r.string().unwrap(), "abcde");
+        assert_eq!(r.string().unwrap(), "");
+        assert_eq!(r.int().unwrap(), -2);
+        assert!(r.0.is_empty());
+    }
+
+    #[test]
+    fn optional_string() {
+        let w = XdrWriter::default().optional_string(Some("abcd")).optional_string(None);
+        assert_eq!(w.0.len(), 4 + 4 + 4 + 4);
+
+        let mut r = XdrReader(&w.0);
+        assert_eq!(r.optional_string().unwrap(), Some("abcd".to_owned()));
+        assert_eq!(r.optional_string().unwrap(), None);
+    }
+
+    #[test]
+    fn domain_round_trip() {
+        let dom = RemoteDomainId {
+            name: "win10".to_owned(),
+            uuid: [0xab; 16],
+            id: 3,
+        };
+        let w = XdrWriter::default().domain(&dom);
+        assert_eq!(w.0.len(), 4 + 8 + 16 + 4);
+
+        let got = XdrReader(&w.0).domain().unwrap();
+        assert_eq!(got.name, dom.name);
+        assert_eq!(got.uuid, dom.uuid);
+        assert_eq!(got.id, dom.id);
+    }
+
+    #[test]
+    fn truncated() {
+        let w = XdrWriter::default().uint(8).opaque(b"abc");
+        match XdrReader(&w.0).string() {
+            Err(Error::Protocol(_)) => {},
+            r => panic!("expected a protocol error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn packet_header() {
+        let p = packet(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 7, &XdrWriter::default().string("a"));
+        let mut r = XdrReader(&p);
+        assert_eq!(r.uint().unwrap() as usize, p.len());
+        assert_eq!(r.uint().unwrap(), REMOTE_PROGRAM);
+        assert_eq!(r.uint().unwrap(), PROTOCOL_VERSION);
+        assert_eq!(r.int().unwrap(), REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME);
+        assert_eq!(r.int().unwrap(), VIR_NET_CALL);
+        assert_eq!(r.uint().unwrap(), 7);
+        assert_eq!(r.int().unwrap(), VIR_NET_OK);
+        assert_eq!(r.string().unwrap(), "a");
+    }
+
+    #[test]
+    fn ok_reply() {
+        let msg = reply_msg(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_GET_XML_DESC, 2, VIR_NET_OK,
+                            XdrWriter::default().string("<domain/>"));
+        let body = reply(&msg, REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_GET_XML_DESC, 2).unwrap().unwrap();
+        assert_eq!(XdrReader(&body).string().unwrap(), "<domain/>");
+    }
+
+    #[test]
+    fn error_reply() {
+        let msg = reply_msg(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 1, VIR_NET_ERROR,
+                            XdrWriter::default().int(42).int(10).optional_string(Some("Domain not found")));
+        match reply(&msg, REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 1) {
+            Err(Error::Libvirt { code, domain, message }) => {
+                assert_eq!((code, domain), (42, 10));
+                assert_eq!(message, "Domain not found");
+            },
+            r => panic!("expected a libvirt error, got {:?}", r),
+        }
+
+        let msg = reply_msg(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 1, VIR_NET_ERROR,
+                            XdrWriter::default().int(1).int(0).optional_string(None));
+        match reply(&msg, REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 1) {
+            Err(Error::Libvirt { message, .. }) => assert_eq!(message, "unknown libvirt error"),
+            r => panic!("expected a libvirt error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn other_messages_skipped() {
+        let msg = reply_msg(REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 1, VIR_NET_OK, XdrWriter::default());
+        assert!(reply(&msg, REMOTE_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 2).unwrap().is_none());
+        assert!(reply(&msg, QEMU_PROGRAM, REMOTE_PROC_DOMAIN_LOOKUP_BY_NAME, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_uri() {
+        match RemoteConnection::open("qemu:///system") {
+            Err(Error::Uri(_)) => {},
+            Err(e) => panic!("expected a URI error, got {}", e),
+            Ok(_) => panic!("expected a URI error"),
+        }
+    }
+
+    #[test]
+    fn close_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut s = Stream {
+            stream: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+            serial: 0,
+        };
+        // a libvirtd that's stopped answering
+        let _peer = listener.accept().unwrap();
+
+        let start = Instant::now();
+        assert!(s.close().is_err());
+        assert!(start.elapsed() < IO_TIMEOUT);
+    }
+
+    /// Needs a libvirtd listening on TCP with `auth_tcp = "none"`
+    #[test]
+    #[ignore]
+    fn test_driver() {
+        let conn = Arc::new(RemoteConnection::open("test+tcp://localhost/default").unwrap());
+        assert!(conn.health().connected);
+        assert!(conn.list_domains().unwrap().contains(&"test".to_owned()));
+
+        let dom = RemoteDomain::lookup(conn.clone(), "test").unwrap();
+        assert!(dom.xml(0).unwrap().contains("<name>test</name>"));
+
+        match RemoteDomain::lookup(conn.clone(), "vfio-motion-missing") {
+            Err(Error::Libvirt { code, .. }) => assert_eq!(code, ::libvirt::VIR_ERR_NO_DOMAIN),
+            Err(e) => panic!("expected a libvirt error, got {}", e),
+            Ok(_) => panic!("expected a libvirt error"),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Keeping a libvirt connection alive across libvirtd restarts.
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Weak, Mutex, RwLock};
+
+use ::libvirt::{self, Connection};
+use ::input::Health;
+
+/// How often the connection is checked, also the libvirt keepalive interval
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Unanswered keepalives before libvirt gives up on the connection
+pub const KEEPALIVE_COUNT: u32 = 3;
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub type ReconnectHook = Box<Fn(&Connection) + Send + Sync>;
+
+/// A libvirt connection that's replaced with a new one when it dies, see `supervise()`
+pub struct Supervisor {
+    uri: String,
+    conn: RwLock<Arc<Connection>>,
+    health: Mutex<Health>,
+    hooks: Mutex<Vec<ReconnectHook>>,
+}
+impl Supervisor {
+    /// Starts libvirt's event loop if nothing has yet, keepalives (and so noticing a dead connection before
+    /// it's used) need one
+    pub fn open(uri: &str) -> Result<Supervisor, ::virt::error::Error> {
+        if let Err(e) = libvirt::spawn_event_loop() {
+            warn!("failed to start the libvirt event loop, dead connections won't be noticed until used: {}", e);
+        }
+        let conn = Connection::open(uri)?;
+        keepalive(&conn);
+        Ok(Supervisor {
+            uri: uri.to_owned(),
+            conn: RwLock::new(Arc::new(conn)),
+            health: Mutex::new(Health { connected: true, ..Default::default() }),
+            hooks: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+    /// The current connection, which might be dead if it hasn't been checked since libvirtd went away
+    pub fn get(&self) -> Arc<Connection> {
+        self.conn.read().unwrap().clone()
+    }
+    pub fn health(&self) -> Health {
+        self.health.lock().unwrap().clone()
+    }
+    /// Call `hook` with every new connection, for things that don't carry over (e.g. event callbacks). Hooks run
+    /// before the new connection replaces the old one, which `get()` still returns.
+    pub fn on_reconnect(&self, hook: ReconnectHook) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    /// Make one attempt to reconnect if the connection is dead, returns whether it's usable afterwards
+    pub fn check(&self) -> bool {
+        if self.get().is_alive().unwrap_or(false) {
+            return true;
+        }
+
+        {
+            let mut health = self.health.lock().unwrap();
+            if health.connected {
+                warn!("lost connection to libvirt on '{}', reconnecting...", self.uri);
+                health.connected = false;
+                health.attempts = 0;
+            }
+        }
+
+        // opening can take as long as libvirt's connect timeout, `health()` shouldn't have to wait for it
+        match Connection::open(&self.uri) {
+            Ok(conn) => {
+                keepalive(&conn);
+                for hook in self.hooks.lock().unwrap().iter() {
+                    hook(&conn);
+                }
+                *self.conn.write().unwrap() = Arc::new(conn);
+
+                let mut health = self.health.lock().unwrap();
+                info!("reconnected to libvirt on '{}' after {} failed attempts", self.uri, health.attempts);
+                health.connected = true;
+                health.reconnects += 1;
+                health.attempts = 0;
+                health.last_error = None;
+                true
+            },
+            Err(e) => {
+                debug!("failed to reconnect to libvirt on '{}': {}", self.uri, e);
+                let mut health = self.health.lock().unwrap();
+                health.attempts += 1;
+                health.last_error = Some(format!("{}", e));
+                false
+            }
+        }
+    }
+}
+
+fn keepalive(conn: &Connection) {
+    // needs the event loop `Supervisor::open()` starts, without one a dead connection is only noticed when it's
+    // used or checked
+    if let Err(e) = conn.set_keep_alive(CHECK_INTERVAL.as_secs() as i32, KEEPALIVE_COUNT) {
+        debug!("libvirt keepalive not enabled: {}", e);
+    }
+}
+
+/// Check `supervisor` every `CHECK_INTERVAL` on a background thread, backing off exponentially while it can't
+/// reconnect. Stops once the supervisor is dropped.
+pub fn supervise(supervisor: Weak<Supervisor>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let alive = match supervisor.upgrade() {
+                Some(s) => s.check(),
+                None => return
+            };
+
+            if alive {
+                backoff = MIN_BACKOFF;
+                thread::sleep(CHECK_INTERVAL);
+            } else {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+    })
+}
@@ -0,0 +1,159 @@
+//! Tracking what is attached where, and reattaching devices whose evdevs vanish and come back (wireless
+//! dongles, KVM switches).
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::thread;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+#[cfg(target_os = "linux")]
+use ::nix::sys::inotify::{Inotify, InitFlags, AddWatchFlags};
+
+use ::evdev;
+#[cfg(target_os = "linux")]
+use ::evdev::INPUT_DIR;
+use ::input::{Error, Hotplug, Persistence, DeviceRequest, Input, Status};
+
+/// How long to give udev to set up permissions and by-id links for a new evdev
+#[cfg(target_os = "linux")]
+pub const UDEV_SETTLE: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, Debug)]
+struct Attachment {
+    /// What the device was attached as, resolved again when its evdevs come back
+    spec: String,
+    hotplug: Hotplug,
+}
+
+/// The lowest evdev node `spec` resolves to, so every way of naming a device ends up with the same key. Falls
+/// back to `spec` itself if it doesn't resolve (e.g. the device has gone away).
+fn canonical(spec: &str) -> String {
+    evdev::resolve(spec).ok()
+        .and_then(|nodes| nodes.iter().filter_map(|n| fs::canonicalize(n).ok()).min())
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| spec.to_owned())
+}
+
+/// Which devices are attached to which domain, keyed by domain and canonical evdev node and kept up to date
+/// by whoever attaches them
+#[derive(Debug, Default)]
+pub struct Registry {
+    attached: Mutex<HashMap<(String, String), Attachment>>,
+}
+impl Registry {
+    pub fn new() -> Registry {
+        Default::default()
+    }
+
+    /// Remove the entries on `domain` for `spec`, whether it was registered under the same name or not
+    fn remove(attached: &mut HashMap<(String, String), Attachment>, domain: &str, spec: &str) {
+        let node = canonical(spec);
+        attached.retain(|&(ref d, ref n), a| d != domain || (*n != node && a.spec != spec));
+    }
+
+    pub fn attached(&self, domain: &str, spec: &str, hotplug: Hotplug) {
+        let mut attached = self.attached.lock().unwrap();
+        Registry::remove(&mut attached, domain, spec);
+        attached.insert((domain.to_owned(), canonical(spec)), Attachment {
+            spec: spec.to_owned(),
+            hotplug,
+        });
+    }
+    pub fn detached(&self, domain: &str, spec: &str) {
+        Registry::remove(&mut self.attached.lock().unwrap(), domain, spec);
+    }
+    fn on(attached: &HashMap<(String, String), Attachment>, domain: &str) -> Vec<(String, Hotplug)> {
+        attached.iter()
+            .filter(|&(&(ref d, _), _)| d == domain)
+            .map(|(_, a)| (a.spec.clone(), a.hotplug))
+            .collect()
+    }
+    /// The specifiers attached to `domain` and how they were attached
+    pub fn get(&self, domain: &str) -> Vec<(String, Hotplug)> {
+        Registry::on(&self.attached.lock().unwrap(), domain)
+    }
+    /// Forget everything attached to `domain`, returning what `get` would have
+    pub fn release(&self, domain: &str) -> Vec<(String, Hotplug)> {
+        let mut attached = self.attached.lock().unwrap();
+        let released = Registry::on(&attached, domain);
+        attached.retain(|&(ref d, _), _| d != domain);
+        released
+    }
+
+    /// Reattach every registered device that has one of the evdev nodes in `created`
+    pub fn reattach(&self, input: &Input, created: &[String]) {
+        let attached = self.attached.lock().unwrap().clone();
+        for ((domain, node), a) in attached {
+            let nodes = match evdev::resolve(&a.spec) {
+                Ok(n) => n,
+                Err(_) => continue
+            };
+            if !nodes.iter().filter_map(|n| fs::canonicalize(n).ok()).any(|n| created.iter().any(|c| n.to_string_lossy() == c.as_str())) {
+                continue;
+            }
+
+            match reattach(input, &domain, &a) {
+                Ok(()) => info!("evdev {:?} reappeared, reattached to '{}'", a.spec, domain),
+                Err(e) => error!("evdev {:?} reappeared but couldn't be reattached to '{}': {}", a.spec, domain, e)
+            }
+
+            // the nodes are probably numbered differently now
+            let mut attached = self.attached.lock().unwrap();
+            if let Some(a) = attached.remove(&(domain.clone(), node)) {
+                attached.insert((domain, canonical(&a.spec)), a);
+            }
+        }
+    }
+}
+
+fn reattach(input: &Input, domain: &str, a: &Attachment) -> Result<(), Error> {
+    let device = input.request(&DeviceRequest {
+        domain: domain.to_owned(),
+        evdev: a.spec.clone(),
+        kind: None,
+        hotplug: Some(a.hotplug),
+        // only the running domain lost it
+        persistence: Some(Persistence::Live),
+    }, a.hotplug)?;
+
+    // the domain still has the device that went away, which is now useless (an evdev input that's set up can't
+    // say where its grab is, but it has to go either way)
+    match device.status() {
+        Status::Detached => {},
+        _ => device.detach()?
+    }
+    device.attach()
+}
+
+/// Watch `/dev/input` for new evdevs on a background thread, reattaching anything in `registry`
+#[cfg(target_os = "linux")]
+pub fn watch(input: Arc<Input>, registry: Arc<Registry>) -> Result<thread::JoinHandle<()>, evdev::Error> {
+    let inotify = Inotify::init(InitFlags::empty()).map_err(evdev::nix_io)?;
+    inotify.add_watch(INPUT_DIR, AddWatchFlags::IN_CREATE).map_err(evdev::nix_io)?;
+
+    Ok(thread::spawn(move || loop {
+        let events = match inotify.read_events() {
+            Ok(e) => e,
+            Err(e) => {
+                error!("failed to read inotify events for {}, no longer watching: {}", INPUT_DIR, e);
+                return;
+            }
+        };
+
+        let created = events.into_iter()
+            .filter_map(|e| e.name)
+            .map(|n| n.to_string_lossy().into_owned())
+            .filter(|n| n.starts_with("event"))
+            .map(|n| format!("{}/{}", INPUT_DIR, n))
+            .collect::<Vec<_>>();
+        if created.is_empty() {
+            continue;
+        }
+
+        debug!("new evdevs: {:?}", created);
+        thread::sleep(UDEV_SETTLE);
+        registry.reattach(&*input, &created);
+    }))
+}
@@ -1,9 +1,19 @@
 use std::error::Error;
+use std::time::Duration;
+use std::path::PathBuf;
+use std::collections::HashMap;
 
 use ::log::LevelFilter;
 use ::config_rs::ConfigError;
 
 use util;
+use ::input::{self, ErrorKind, Hotplug, Grab, QmpBus, Persistence, Templates, DeviceTemplate};
+use ::domain_xml::PciAddress;
+use ::domain_xml::Template;
+use ::evdev::{EvdevPolicy, EvdevInfo};
+use ::lifecycle::DomainPolicy;
+use ::mock::{MockInput, MockOp};
+use ::qmp::QmpInput;
 
 #[cfg(build = "debug")]
 const ROCKET_ENVIRONMENT: ::rocket::config::Environment = ::rocket::config::Environment::Development;
@@ -24,11 +34,104 @@ impl RocketConfig {
             .unwrap()
     }
 }
+/// What the server hands requests to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Native,
+    /// QEMU instances that aren't managed by libvirt, through the sockets in `qmp_sockets`
+    Qmp,
+    /// In-memory, see `MockConfig`
+    Mock,
+}
+/// Fake domains and evdevs for `backend = "mock"`
+#[derive(Debug, Default, Deserialize)]
+pub struct MockConfig {
+    #[serde(default)]
+    domains: Vec<String>,
+    #[serde(default)]
+    evdevs: Vec<EvdevInfo>,
+    /// Milliseconds every operation takes
+    #[serde(default)]
+    latency: u64,
+    /// Evdevs attached from the start, keyed by domain
+    #[serde(default)]
+    attached: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    failures: Vec<MockFailure>,
+}
+/// An operation that always fails in `backend = "mock"`
+#[derive(Debug, Deserialize)]
+pub struct MockFailure {
+    op: MockOp,
+    /// Unused for `op = "domains"`
+    #[serde(default)]
+    evdev: String,
+    kind: ErrorKind,
+}
+#[derive(Debug, Default, Deserialize)]
+pub struct DomainConfig {
+    #[serde(default)]
+    hotplug: Option<Hotplug>,
+    /// Name of an entry in `templates`
+    #[serde(default)]
+    template: Option<String>,
+    /// Device specifiers to attach when the domain starts
+    #[serde(default)]
+    devices: Vec<String>,
+    #[serde(default)]
+    attach_on_start: bool,
+    #[serde(default)]
+    release_on_stop: bool,
+    #[serde(default)]
+    release_on_pause: bool,
+    /// Resume the domain if it's paused before attaching to it
+    #[serde(default)]
+    resume: bool,
+}
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceConfig {
+    /// Overrides the domain's `hotplug`
+    #[serde(default)]
+    hotplug: Option<Hotplug>,
+    /// Name of an entry in `templates`
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    address: Option<PciAddress>,
+    #[serde(default)]
+    persistence: Option<Persistence>,
+}
 #[derive(Debug, Deserialize)]
 pub struct Config {
     log_level: String,
+    backend: Backend,
+    #[serde(default)]
+    mock: MockConfig,
     libvirt_uri: String,
+    hotplug: Hotplug,
+    /// Used by `hotplug = "evdev"`
+    #[serde(default)]
+    grab: Grab,
+    /// Used by `hotplug = "qmp"` and `backend = "qmp"`
+    #[serde(default)]
+    qmp_bus: QmpBus,
+    /// QMP socket of each domain for `backend = "qmp"`, keyed by domain name
+    #[serde(default)]
+    qmp_sockets: HashMap<String, PathBuf>,
     http: RocketConfig,
+    #[serde(default)]
+    evdev: EvdevPolicy,
+    /// Reattach devices when their evdevs disappear and come back
+    reattach: bool,
+    #[serde(default)]
+    templates: HashMap<String, Template>,
+    #[serde(default)]
+    domains: HashMap<String, DomainConfig>,
+    /// Keyed by device specifier
+    #[serde(default)]
+    devices: HashMap<String, DeviceConfig>,
+    persistence: Persistence,
 
     #[serde(skip)]
     _log_level: Option<LevelFilter>,
@@ -44,10 +147,99 @@ impl Config {
             }
         }
     }
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+    pub fn mock(&self) -> MockInput {
+        let mut input = MockInput::new().latency(Duration::from_millis(self.mock.latency));
+        for domain in &self.mock.domains {
+            input = input.domain(domain);
+        }
+        for evdev in &self.mock.evdevs {
+            input = input.evdev(evdev.clone());
+        }
+        for (domain, evdevs) in &self.mock.attached {
+            for evdev in evdevs {
+                input = input.attached(domain, evdev);
+            }
+        }
+        for failure in &self.mock.failures {
+            input.fail(failure.op, &failure.evdev, failure.kind);
+        }
+        input
+    }
+    pub fn qmp(&self) -> QmpInput {
+        QmpInput::new(self.qmp_sockets.clone())
+            .evdev_policy(self.evdev.clone())
+            .bus(self.qmp_bus.clone())
+    }
     pub fn libvirt_uri(&self) -> &str {
         &self.libvirt_uri
     }
     pub fn http(&self) -> &RocketConfig {
         &self.http
     }
+    /// Global persistence and per-device overrides
+    pub fn persistence(&self) -> (Persistence, HashMap<String, Persistence>) {
+        let devices = self.devices.iter()
+            .filter_map(|(spec, d)| d.persistence.map(|p| (spec.clone(), p)))
+            .collect();
+        (self.persistence, devices)
+    }
+    /// Per-device hotplug methods, see `hotplug()` for the domain's
+    pub fn device_hotplug(&self) -> HashMap<String, Hotplug> {
+        self.devices.iter()
+            .filter_map(|(spec, d)| d.hotplug.map(|h| (spec.clone(), h)))
+            .collect()
+    }
+    pub fn grab(&self) -> Grab {
+        self.grab
+    }
+    pub fn qmp_bus(&self) -> &QmpBus {
+        &self.qmp_bus
+    }
+    pub fn reattach(&self) -> bool {
+        self.reattach
+    }
+    pub fn evdev_policy(&self) -> &EvdevPolicy {
+        &self.evdev
+    }
+    pub fn templates(&self) -> Result<Templates, input::Error> {
+        let mut templates = Templates::new(self.templates.clone());
+        for (name, domain) in &self.domains {
+            if let Some(ref t) = domain.template {
+                templates = templates.domain(name, t)?;
+            }
+        }
+        for (spec, device) in &self.devices {
+            templates = templates.device(spec, DeviceTemplate {
+                template: device.template.clone(),
+                address: device.address,
+            })?;
+        }
+        Ok(templates)
+    }
+    pub fn policies(&self) -> HashMap<String, DomainPolicy> {
+        self.domains.iter()
+            .map(|(name, d)| (name.clone(), DomainPolicy {
+                devices: d.devices.clone(),
+                hotplug: d.hotplug.unwrap_or(self.hotplug),
+                attach_on_start: d.attach_on_start,
+                release_on_stop: d.release_on_stop,
+                release_on_pause: d.release_on_pause,
+                resume: d.resume,
+            }))
+            .collect()
+    }
+    pub fn hotplug(&self, domain: &str) -> Hotplug {
+        self.domains.get(domain)
+            .and_then(|d| d.hotplug)
+            .unwrap_or(self.hotplug)
+    }
+    /// How `spec` is hotplugged into `domain` when a request doesn't say
+    pub fn spec_hotplug(&self, domain: &str, spec: &str) -> Hotplug {
+        self.devices.get(spec)
+            .and_then(|d| d.hotplug)
+            .unwrap_or_else(|| self.hotplug(domain))
+    }
 }
@@ -2,6 +2,7 @@
 #![feature(plugin)]
 #![plugin(rocket_codegen)]
 use std::process;
+use std::sync::Arc;
 use std::error::Error;
 
 #[macro_use]
@@ -15,6 +16,7 @@ extern crate serde_json;
 
 extern crate config as config_rs;
 extern crate serde;
+#[cfg(feature = "native")]
 extern crate virt;
 extern crate simple_signal;
 extern crate rocket;
@@ -24,36 +26,66 @@ extern crate vfio_motion_common;
 
 use simple_signal::Signal;
 
-use vfio_motion_common::{libvirt, input};
+#[cfg(feature = "native")]
+use vfio_motion_common::{libvirt, supervisor};
+use vfio_motion_common::{input, evdev, watch, lifecycle, domain_xml, mock, qmp};
 
 pub mod util;
 pub mod config;
 mod server;
 
-use config::Config;
+use config::{Config, Backend};
 
-fn dummy_virt_handler(_ctx: Box<Option<String>>, err: virt::error::Error) {
-    trace!("libvirt error: {}", err);
-}
 pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    // Prevent libvirt built-in error logging
-    libvirt::set_error_handler(Box::new(None), dummy_virt_handler);
-
-    unsafe {
-        input::open_native_global_conn(config.libvirt_uri().into())?
-    }
-    let conn = libvirt::Connection::open(config.libvirt_uri())?;
     simple_signal::set_handler(&[Signal::Int, Signal::Term], |_signals| {
         info!("shutting down...");
-        unsafe {
-            match input::close_native_global_conn() {
-                Err(e) => error!("failed to close global connection: {}", e),
-                _ => process::exit(-1)
-            };
-        }
         process::exit(0);
     });
-    debug!("Opened connection to libvirt on '{}'", conn.get_uri()?);
 
-    Err(Box::new(server::run(config.http().get())))
+    match config.backend() {
+        Backend::Native => run_native(config),
+        Backend::Qmp => {
+            let input = Arc::new(config.qmp());
+            Err(Box::new(server::run(config, input, Arc::new(watch::Registry::new()), None)))
+        },
+        Backend::Mock => {
+            warn!("using the mock backend, no devices will actually be passed through");
+            let input = Arc::new(config.mock());
+            Err(Box::new(server::run(config, input, Arc::new(watch::Registry::new()), None)))
+        }
+    }
+}
+#[cfg(feature = "native")]
+fn run_native(config: Config) -> Result<(), Box<dyn Error>> {
+    // Prevent libvirt built-in error logging
+    libvirt::set_error_sink(|err| trace!("libvirt error: {}", err));
+    // Must be registered before the connection is opened for it to get events
+    libvirt::spawn_event_loop()?;
+
+    let conn = Arc::new(supervisor::Supervisor::open(config.libvirt_uri())?);
+    debug!("Opened connection to libvirt on '{}'", conn.get().get_uri()?);
+    supervisor::supervise(Arc::downgrade(&conn));
+
+    let (persistence, device_persistence) = config.persistence();
+    let input = input::NativeInput::from_conn(conn, Default::default())
+        .evdev_policy(config.evdev_policy().clone())
+        .templates(config.templates()?)
+        .grab(config.grab())
+        .qmp_bus(config.qmp_bus().clone())
+        .persistence(persistence, device_persistence)
+        .device_hotplug(config.device_hotplug());
+
+    let registry = Arc::new(watch::Registry::new());
+    if config.reattach() {
+        watch::watch(Arc::new(input.clone()), registry.clone())?;
+        info!("watching {} to reattach devices that reappear", evdev::INPUT_DIR);
+    }
+
+    let lifecycle = lifecycle::Lifecycle::new(input.clone(), registry.clone(), config.policies());
+    lifecycle.watch()?;
+    Err(Box::new(server::run(config, Arc::new(input), registry, Some(lifecycle))))
+}
+#[cfg(not(feature = "native"))]
+fn run_native(_config: Config) -> Result<(), Box<dyn Error>> {
+    Err(Box::new(input::Error::Disabled("native")))
 }
@@ -61,7 +61,11 @@ fn args<'a>() -> clap::ArgMatches<'a> {
 fn load_config(args: clap::ArgMatches) -> Result<Config, ConfigError> {
     let mut config = ConfigRs::default();
     config.set_default("log_level", DEFAULT_LOG_LEVEL.to_string())?;
+    config.set_default("backend", "native")?;
     config.set_default("libvirt_uri", "qemu:///system")?;
+    config.set_default("hotplug", "libvirt")?;
+    config.set_default("persistence", "live")?;
+    config.set_default("reattach", true)?;
     config.set_default("http.address", "127.0.0.1")?;
     config.set_default("http.port", 3020)?;
 
@@ -81,7 +85,10 @@ fn load_config(args: clap::ArgMatches) -> Result<Config, ConfigError> {
     config.merge(config_rs::Environment::with_prefix("VFIO_MOTION"))?;
 
 
-    config.try_into()
+    let config: Config = config.try_into()?;
+    // catch templates that don't exist before we start serving
+    config.templates().map_err(|e| ConfigError::Message(e.to_string()))?;
+    Ok(config)
 }
 
 fn main() {
@@ -1,47 +1,102 @@
-use ::rocket::config::Config;
+use ::rocket::{Rocket, State};
 use ::rocket::error::{LaunchError};
-use ::rocket::http::Status;
+use ::rocket::http::Status as HttpStatus;
 use ::rocket::response::status;
 use ::rocket_contrib::{SerdeError, Json};
 
-use ::input::{self, Device, NativeDevice, Domains, NativeDomains};
+use std::sync::Arc;
 
-macro_rules! error_msg {
-    ($name:ident, $status:ident, $err:ty) => (
-        pub fn $name(err: $err) -> status::Custom<Json<ErrorMsg>> {
-            status::Custom(Status::$status, Json(ErrorMsg {
-                message: format!("{}", err)
-            }))
-        }
-    )
-}
+use ::input::{self, Input, Device, DeviceRequest, Group, GroupRequest, MoveRequest, Target, Status, ErrorKind};
+use ::watch::Registry;
+#[cfg(feature = "native")]
+use ::lifecycle::Lifecycle;
+use ::config::Config;
+use ::util;
 
 #[derive(Debug, Serialize)]
 pub struct ErrorMsg {
-    message: String
+    code: ErrorKind,
+    message: String,
+    /// Devices left in the wrong state by a failed group switch or move
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stranded: Vec<String>,
+    /// For a failed move, whether the devices are back on the domain they came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restored: Option<bool>,
 }
 impl ErrorMsg {
-    error_msg!(serde, BadRequest, SerdeError);
-    error_msg!(input, InternalServerError, input::Error);
+    pub fn serde(err: SerdeError) -> status::Custom<Json<ErrorMsg>> {
+        status::Custom(HttpStatus::BadRequest, Json(ErrorMsg {
+            code: ErrorKind::BadRequest,
+            message: format!("{}", err),
+            stranded: Vec::new(),
+            restored: None,
+        }))
+    }
+    pub fn input(err: input::Error) -> status::Custom<Json<ErrorMsg>> {
+        let kind = err.kind();
+        status::Custom(util::error_status(kind), Json(ErrorMsg {
+            code: kind,
+            message: format!("{}", err),
+            stranded: err.stranded().to_vec(),
+            restored: err.restored(),
+        }))
+    }
+}
+
+/// Stands in for `lifecycle::Lifecycle` without the native backend, never constructed
+#[cfg(not(feature = "native"))]
+pub enum Lifecycle {}
+
+/// Only the native backend has lifecycle policies
+#[cfg(feature = "native")]
+fn before_attach(lifecycle: &Option<Lifecycle>, domain: &str) -> Result<(), status::Custom<Json<ErrorMsg>>> {
+    match *lifecycle {
+        Some(ref l) => l.before_attach(domain).map_err(ErrorMsg::input),
+        None => Ok(())
+    }
+}
+#[cfg(not(feature = "native"))]
+fn before_attach(_lifecycle: &Option<Lifecycle>, _domain: &str) -> Result<(), status::Custom<Json<ErrorMsg>>> {
+    Ok(())
 }
 
 #[post("/device/status", data="<device>")]
-fn attached(device: Result<Json<NativeDevice>, SerdeError>) -> Result<Json, status::Custom<Json<ErrorMsg>>> {
+fn attached(config: State<Config>, input: State<Arc<Input>>, device: Result<Json<DeviceRequest>, SerdeError>) -> Result<Json, status::Custom<Json<ErrorMsg>>> {
     match device {
-        Ok(Json(d)) => {
-            debug!("handling status of evdev at '{:?}'", d.evdev());
-            Ok(Json(json!({ "attached": d.attached() })))
+        Ok(Json(req)) => {
+            debug!("handling status of evdev at '{:?}'", req.evdev);
+            let d = input.request(&req, config.hotplug(&req.domain)).map_err(ErrorMsg::input)?;
+            let mut status = match d.status() {
+                Status::Attached => json!({ "status": "attached" }),
+                Status::Detached => json!({ "status": "detached" }),
+                Status::Unknown(e) => {
+                    warn!("status of evdev at '{}' unknown: {}", d.evdev(), e);
+                    json!({ "status": "unknown", "message": format!("{}", e) })
+                }
+            };
+            status["persistent"] = match d.persistent_status() {
+                Status::Attached => json!("attached"),
+                Status::Detached => json!("detached"),
+                Status::Unknown(_) => json!("unknown"),
+            };
+            Ok(Json(status))
         },
         Err(e) => Err(ErrorMsg::serde(e))
     }
 }
 #[post("/device", data="<device>")]
-fn attach(device: Result<Json<NativeDevice>, SerdeError>) -> Result<status::NoContent, status::Custom<Json<ErrorMsg>>> {
+fn attach(config: State<Config>, input: State<Arc<Input>>, registry: State<Arc<Registry>>, lifecycle: State<Option<Lifecycle>>, device: Result<Json<DeviceRequest>, SerdeError>) -> Result<status::NoContent, status::Custom<Json<ErrorMsg>>> {
     match device {
-        Ok(Json(d)) => {
-            debug!("handling attach of evdev at '{:?}'", d.evdev());
-            match d.attach() {
-                Ok(()) => Ok(status::NoContent),
+        Ok(Json(req)) => {
+            debug!("handling attach of evdev at '{:?}'", req.evdev);
+            let hotplug = req.hotplug.unwrap_or(config.spec_hotplug(&req.domain, &req.evdev));
+            before_attach(&lifecycle, &req.domain)?;
+            match input.request(&req, hotplug).and_then(|d| d.attach()) {
+                Ok(()) => {
+                    registry.attached(&req.domain, &req.evdev, hotplug);
+                    Ok(status::NoContent)
+                },
                 Err(e) => Err(ErrorMsg::input(e))
             }
         },
@@ -49,12 +104,15 @@ fn attach(device: Result<Json<NativeDevice>, SerdeError>) -> Result<status::NoCo
     }
 }
 #[delete("/device", data="<device>")]
-fn detach(device: Result<Json<NativeDevice>, SerdeError>) -> Result<status::NoContent, status::Custom<Json<ErrorMsg>>> {
+fn detach(config: State<Config>, input: State<Arc<Input>>, registry: State<Arc<Registry>>, device: Result<Json<DeviceRequest>, SerdeError>) -> Result<status::NoContent, status::Custom<Json<ErrorMsg>>> {
     match device {
-        Ok(Json(d)) => {
-            debug!("handling detach of evdev at '{:?}'", d.evdev());
-            match d.detach() {
-                Ok(()) => Ok(status::NoContent),
+        Ok(Json(req)) => {
+            debug!("handling detach of evdev at '{:?}'", req.evdev);
+            match input.request(&req, config.hotplug(&req.domain)).and_then(|d| d.detach()) {
+                Ok(()) => {
+                    registry.detached(&req.domain, &req.evdev);
+                    Ok(status::NoContent)
+                },
                 Err(e) => Err(ErrorMsg::input(e))
             }
         },
@@ -62,13 +120,106 @@ fn detach(device: Result<Json<NativeDevice>, SerdeError>) -> Result<status::NoCo
     }
 }
 
+#[post("/group", data="<group>")]
+fn switch_group(config: State<Config>, input: State<Arc<Input>>, registry: State<Arc<Registry>>, lifecycle: State<Option<Lifecycle>>, group: Result<Json<GroupRequest>, SerdeError>) -> Result<Json, status::Custom<Json<ErrorMsg>>> {
+    match group {
+        Ok(Json(req)) => {
+            debug!("handling switch of {:?} on '{}' to {:?}", req.evdevs, req.domain, req.target);
+            if req.target != Target::Detached {
+                before_attach(&lifecycle, &req.domain)?;
+            }
+            match input.request_group(&req, config.hotplug(&req.domain)).and_then(|g| g.switch(req.target)) {
+                Ok(attached) => {
+                    for spec in &req.evdevs {
+                        if attached {
+                            registry.attached(&req.domain, spec, req.hotplug.unwrap_or(config.spec_hotplug(&req.domain, spec)));
+                        } else {
+                            registry.detached(&req.domain, spec);
+                        }
+                    }
+                    Ok(Json(json!({ "attached": attached })))
+                },
+                Err(e) => Err(ErrorMsg::input(e))
+            }
+        },
+        Err(e) => Err(ErrorMsg::serde(e))
+    }
+}
+
+#[post("/move", data="<moving>")]
+fn move_devices(config: State<Config>, input: State<Arc<Input>>, registry: State<Arc<Registry>>, lifecycle: State<Option<Lifecycle>>, moving: Result<Json<MoveRequest>, SerdeError>) -> Result<status::NoContent, status::Custom<Json<ErrorMsg>>> {
+    match moving {
+        Ok(Json(req)) => {
+            debug!("handling move of {:?} from '{}' to '{}'", req.evdevs, req.from, req.to);
+            before_attach(&lifecycle, &req.to)?;
+
+            let res = input.request_group(&req.source(), config.hotplug(&req.from))
+                .and_then(|from| input.request_group(&req.destination(), config.hotplug(&req.to)).map(|to| (from, to)))
+                .and_then(|(from, to)| input::move_group(&*from, &*to));
+            match res {
+                Ok(()) => {
+                    for spec in &req.evdevs {
+                        registry.detached(&req.from, spec);
+                        registry.attached(&req.to, spec, req.hotplug.unwrap_or(config.spec_hotplug(&req.to, spec)));
+                    }
+                    Ok(status::NoContent)
+                },
+                Err(e) => {
+                    // the devices could be on either domain, both or neither now
+                    if let input::Error::Move { restored: false, .. } = e {
+                        recount(&config, &**input, &registry, &req);
+                    }
+                    Err(ErrorMsg::input(e))
+                }
+            }
+        },
+        Err(e) => Err(ErrorMsg::serde(e))
+    }
+}
+
+/// Register each of `req`'s devices with whichever of its domains still has it after a move that couldn't put
+/// everything back
+fn recount(config: &Config, input: &Input, registry: &Registry, req: &MoveRequest) {
+    for domain in &[&req.from, &req.to] {
+        for spec in &req.evdevs {
+            let status = input.request(&DeviceRequest {
+                domain: domain.to_string(),
+                evdev: spec.clone(),
+                kind: req.kind,
+                hotplug: req.hotplug,
+                persistence: req.persistence,
+            }, config.hotplug(domain)).map(|d| d.status());
+            match status {
+                Ok(Status::Detached) => registry.detached(domain, spec),
+                // an unknown status is kept so the device isn't forgotten while it might still be attached
+                Ok(_) => registry.attached(domain, spec, req.hotplug.unwrap_or(config.spec_hotplug(domain, spec))),
+                Err(e) => error!("couldn't tell whether {:?} is still on '{}': {}", spec, domain, e)
+            }
+        }
+    }
+}
+
 #[get("/domains")]
-fn domains() -> Result<Json, status::Custom<Json<ErrorMsg>>> {
-    match NativeDomains::new(input::get_native_global_conn().unwrap()).list() {
+fn domains(input: State<Arc<Input>>) -> Result<Json, status::Custom<Json<ErrorMsg>>> {
+    match input.domains().list() {
         Ok(doms) => Ok(Json(json!(doms))),
         Err(e) => Err(ErrorMsg::input(e))
     }
 }
+#[get("/evdevs")]
+fn evdevs(input: State<Arc<Input>>) -> Result<Json, status::Custom<Json<ErrorMsg>>> {
+    match input.evdevs() {
+        Ok(evdevs) => Ok(Json(json!(evdevs))),
+        Err(e) => Err(ErrorMsg::input(e))
+    }
+}
+
+#[get("/health")]
+fn health(input: State<Arc<Input>>) -> status::Custom<Json> {
+    let health = input.health();
+    let status = if health.connected { HttpStatus::Ok } else { HttpStatus::ServiceUnavailable };
+    status::Custom(status, Json(json!(health)))
+}
 
 #[catch(404)]
 fn not_found() -> Json {
@@ -79,10 +230,172 @@ fn internal_error() -> Json {
     Json(json!({ "message": "internal server error" }))
 }
 
-pub fn run(config: Config) -> LaunchError {
+fn rocket(config: Config, input: Arc<Input>, registry: Arc<Registry>, lifecycle: Option<Lifecycle>) -> Rocket {
     // Unfortunately since were using the same log framework as Rocket, log to false has no effect
-    ::rocket::custom(config, ::log::max_level() >= ::log::LevelFilter::Debug)
-        .mount("/", routes![attached, attach, detach, domains])
+    ::rocket::custom(config.http().get(), ::log::max_level() >= ::log::LevelFilter::Debug)
+        .manage(config)
+        .manage(input)
+        .manage(registry)
+        .manage(lifecycle)
+        .mount("/", routes![attached, attach, detach, switch_group, move_devices, domains, evdevs, health])
         .catch(catchers![not_found, internal_error])
-        .launch()
+}
+pub fn run(config: Config, input: Arc<Input>, registry: Arc<Registry>, lifecycle: Option<Lifecycle>) -> LaunchError {
+    rocket(config, input, registry, lifecycle).launch()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ::config_rs::{self, FileFormat};
+    use ::rocket::http::{ContentType, Status as HttpStatus};
+    use ::rocket::local::{Client, LocalRequest, LocalResponse};
+    use ::serde_json::{self, Value};
+
+    use ::input::Hotplug;
+    use ::watch::Registry;
+    use ::config::Config;
+
+    const CONFIG: &'static str = r#"
+log_level = "info"
+backend = "mock"
+libvirt_uri = "qemu:///system"
+hotplug = "libvirt"
+persistence = "live"
+reattach = false
+
+[http]
+address = "127.0.0.1"
+port = 3020
+
+[mock]
+domains = ["a", "b"]
+
+[mock.attached]
+a = ["/dev/input/event0"]
+
+[[mock.failures]]
+op = "attach"
+evdev = "/dev/input/event9"
+kind = "busy"
+
+[[mock.failures]]
+op = "detach"
+evdev = "/dev/input/event8"
+kind = "busy"
+"#;
+
+    fn client() -> Client {
+        client_with(Arc::new(Registry::new()))
+    }
+    fn client_with(registry: Arc<Registry>) -> Client {
+        let mut config = config_rs::Config::default();
+        config.merge(config_rs::File::from_str(CONFIG, FileFormat::Toml)).unwrap();
+        let config: Config = config.try_into().unwrap();
+
+        let input = Arc::new(config.mock());
+        Client::new(super::rocket(config, input, registry, None)).unwrap()
+    }
+    fn json(res: &mut LocalResponse) -> Value {
+        serde_json::from_str(&res.body_string().unwrap()).unwrap()
+    }
+    fn send<'c>(req: LocalRequest<'c>, body: &str) -> LocalResponse<'c> {
+        req.header(ContentType::JSON).body(body).dispatch()
+    }
+
+    #[test]
+    fn domains() {
+        let client = client();
+        let mut res = client.get("/domains").dispatch();
+        assert_eq!(res.status(), HttpStatus::Ok);
+        assert_eq!(json(&mut res), json!(["a", "b"]));
+
+        let mut res = client.get("/health").dispatch();
+        assert_eq!(res.status(), HttpStatus::Ok);
+        assert_eq!(json(&mut res)["connected"], json!(true));
+    }
+
+    #[test]
+    fn attach_detach() {
+        let client = client();
+        let body = r#"{ "domain": "a", "evdev": "/dev/input/event1" }"#;
+
+        let mut res = send(client.post("/device/status"), body);
+        assert_eq!(res.status(), HttpStatus::Ok);
+        assert_eq!(json(&mut res)["status"], json!("detached"));
+
+        assert_eq!(send(client.post("/device"), body).status(), HttpStatus::NoContent);
+        let mut res = send(client.post("/device/status"), body);
+        assert_eq!(json(&mut res)["status"], json!("attached"));
+
+        let mut res = send(client.post("/device"), body);
+        assert_eq!(res.status(), HttpStatus::Conflict);
+        assert_eq!(json(&mut res)["code"], json!("busy"));
+
+        assert_eq!(send(client.delete("/device"), body).status(), HttpStatus::NoContent);
+        let mut res = send(client.delete("/device"), body);
+        assert_eq!(res.status(), HttpStatus::NotFound);
+        assert_eq!(json(&mut res)["code"], json!("missing"));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let client = client();
+        let mut res = send(client.post("/device"), r#"{ "domain": "c", "evdev": "/dev/input/event1" }"#);
+        assert_eq!(res.status(), HttpStatus::NotFound);
+        assert_eq!(json(&mut res)["code"], json!("no_domain"));
+
+        let mut res = send(client.post("/device"), r#"{ "domain": "a" }"#);
+        assert_eq!(res.status(), HttpStatus::BadRequest);
+        assert_eq!(json(&mut res)["code"], json!("bad_request"));
+    }
+
+    #[test]
+    fn group_rolls_back() {
+        let client = client();
+        let mut res = send(client.post("/group"),
+                           r#"{ "domain": "b", "evdevs": ["/dev/input/event1", "/dev/input/event9"], "target": "attached" }"#);
+        assert_eq!(res.status(), HttpStatus::Conflict);
+        let msg = json(&mut res);
+        assert_eq!(msg["code"], json!("busy"));
+        assert!(msg.get("stranded").is_none());
+
+        let mut res = send(client.post("/device/status"), r#"{ "domain": "b", "evdev": "/dev/input/event1" }"#);
+        assert_eq!(json(&mut res)["status"], json!("detached"));
+    }
+
+    #[test]
+    fn move_rolls_back() {
+        let client = client();
+        let mut res = send(client.post("/move"), r#"{ "from": "a", "to": "b", "evdevs": ["/dev/input/event0", "/dev/input/event9"] }"#);
+        assert_eq!(res.status(), HttpStatus::Conflict);
+        assert_eq!(json(&mut res)["restored"], json!(true));
+
+        let mut res = send(client.post("/device/status"), r#"{ "domain": "a", "evdev": "/dev/input/event0" }"#);
+        assert_eq!(json(&mut res)["status"], json!("attached"));
+    }
+
+    #[test]
+    fn move_registers_leftovers() {
+        let registry = Arc::new(Registry::new());
+        let client = client_with(registry.clone());
+        // event8 can't be taken off `b` again once it's there
+        let mut res = send(client.post("/move"), r#"{ "from": "a", "to": "b", "evdevs": ["/dev/input/event8", "/dev/input/event9"] }"#);
+        assert_eq!(res.status(), HttpStatus::Conflict);
+        assert_eq!(json(&mut res)["restored"], json!(false));
+
+        assert!(registry.get("a").is_empty());
+        assert_eq!(registry.get("b"), vec![("/dev/input/event8".to_owned(), Hotplug::Libvirt)]);
+    }
+
+    #[test]
+    fn moves() {
+        let client = client();
+        let res = send(client.post("/move"), r#"{ "from": "a", "to": "b", "evdevs": ["/dev/input/event0"] }"#);
+        assert_eq!(res.status(), HttpStatus::NoContent);
+
+        let mut res = send(client.post("/device/status"), r#"{ "domain": "b", "evdev": "/dev/input/event0" }"#);
+        assert_eq!(json(&mut res)["status"], json!("attached"));
+    }
 }
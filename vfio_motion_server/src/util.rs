@@ -1,5 +1,8 @@
 use ::log::LevelFilter;
 use ::rocket::config::LoggingLevel;
+use ::rocket::http::Status;
+
+use ::input::ErrorKind;
 
 pub fn rocket_log_level(level: LevelFilter) -> LoggingLevel {
     match level {
@@ -8,3 +11,17 @@ pub fn rocket_log_level(level: LevelFilter) -> LoggingLevel {
         LevelFilter::Debug | LevelFilter::Trace => LoggingLevel::Debug,
     }
 }
+
+pub fn error_status(kind: ErrorKind) -> Status {
+    match kind {
+        ErrorKind::BadRequest | ErrorKind::InvalidDevice => Status::BadRequest,
+        ErrorKind::Busy => Status::Conflict,
+        ErrorKind::Missing | ErrorKind::NoDomain => Status::NotFound,
+        ErrorKind::NotRunning => Status::PreconditionFailed,
+        ErrorKind::PermissionDenied => Status::Forbidden,
+        ErrorKind::ConnectionLost => Status::ServiceUnavailable,
+        ErrorKind::Monitor => Status::BadGateway,
+        ErrorKind::Unsupported => Status::NotImplemented,
+        ErrorKind::Other => Status::InternalServerError,
+    }
+}